@@ -1,17 +1,150 @@
-use std::fmt::{Display, Formatter};
+use std::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
 
-use log::info;
-use reqwest::Url;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use reqwest::{
+    StatusCode, Url,
+    header::{ETAG, HeaderMap, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RETRY_AFTER},
+};
 use serde::Deserialize;
 
-use crate::xkcd::Xkcd;
+use crate::{image::ImageVariant, xkcd::Xkcd};
 
-lazy_static::lazy_static!(
-    static ref XKCD_URL: Url = Url::parse("https://xkcd.com/").unwrap();
-);
+pub const XKCD_URL: &str = "https://xkcd.com/";
 
 const JSON: &str = "info.0.json";
 
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("not found")]
+    NotFound,
+    #[error("rate limited")]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("server error: {status}")]
+    ServerError {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
+    #[error("unexpected status: {0}")]
+    UnexpectedStatus(StatusCode),
+    #[error("failed to decode response: {0}")]
+    Decode(#[from] serde_json::Error),
+    #[error("network error: {0}")]
+    Network(#[from] reqwest::Error),
+    #[error("invalid URL: {0}")]
+    InvalidUrl(String),
+}
+
+impl ApiError {
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ApiError::RateLimited { .. } | ApiError::ServerError { .. } | ApiError::Network(_)
+        )
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ApiError::RateLimited { retry_after } | ApiError::ServerError { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the next attempt: `Retry-After` if the server sent one, exponential backoff with full jitter
+    /// otherwise. `None` if the server asks to wait longer than `max_delay`.
+    fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        match retry_after {
+            Some(retry_after) => (retry_after <= self.max_delay).then_some(retry_after),
+            None => {
+                let backoff = self.base_delay.saturating_mul(2u32.saturating_pow(attempt - 1));
+                let cap = backoff.min(self.max_delay).as_millis() as u64;
+                Some(Duration::from_millis(rand::random_range(0..=cap)))
+            }
+        }
+    }
+
+    async fn run<T, F, Fut>(&self, what: &str, mut f: F) -> Result<T, ApiError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>, {
+        let mut attempt = 1;
+        loop {
+            let e = match f().await {
+                Err(e) if e.is_retryable() && attempt < self.max_attempts => e,
+                result => return result,
+            };
+            let Some(delay) = self.delay(attempt, e.retry_after()) else {
+                return Err(e);
+            };
+            warn!(
+                "Failed to fetch {what}: {e}, retrying in {delay:?} ({attempt}/{})",
+                self.max_attempts
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+/// `ETag` and `Last-Modified` of a response, sent back to only get the resource again when it changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    fn from_response(resp: &reqwest::Response) -> Self {
+        let header = |name| {
+            resp.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Fetched<T> {
+    pub value: T,
+    pub validators: Validators,
+}
+
+impl<T> Fetched<T> {
+    fn try_map<U, E>(self, f: impl FnOnce(T) -> Result<U, E>) -> Result<Fetched<U>, E> {
+        Ok(Fetched {
+            value: f(self.value)?,
+            validators: self.validators,
+        })
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct XkcdApiResponse {
     pub num: u32,
@@ -34,26 +167,252 @@ impl Display for XkcdApiResponse {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result { write!(f, "Xkcd #{}: {}", self.num, self.title,) }
 }
 
-pub async fn fetch_latest(client: &reqwest::Client) -> Result<XkcdApiResponse, anyhow::Error> {
+/// `segments` appended to the path of `base_url`. They are used as they are, so they have to be percent-encoded
+/// already.
+fn api_url(base_url: &Url, segments: &[&str]) -> Result<Url, ApiError> {
+    if base_url.cannot_be_a_base() {
+        return Err(ApiError::InvalidUrl(format!("{base_url} cannot be used as a base URL")));
+    }
+    let mut url = base_url.clone();
+    url.set_path(&format!(
+        "{}/{}",
+        base_url.path().trim_end_matches('/'),
+        segments.join("/")
+    ));
+    Ok(url)
+}
+
+/// Image URL from the API response, with scheme, host and port replaced by `image_base_url` when it is set.
+fn image_url(comic: &Xkcd, image_base_url: Option<&Url>, variant: ImageVariant) -> Result<Url, ApiError> {
+    let url = Url::parse(&comic.image_url).map_err(|e| ApiError::InvalidUrl(format!("{}: {e}", comic.image_url)))?;
+    let mut segments = url.path_segments().map(|s| s.collect::<Vec<_>>()).unwrap_or_default();
+    let file_name = segments.pop().map(|name| variant.file_name(name)).unwrap_or_default();
+    segments.push(&file_name);
+    match image_base_url {
+        Some(base_url) => api_url(base_url, &segments),
+        None => {
+            let mut variant_url = url.clone();
+            variant_url.set_path(&segments.join("/"));
+            Ok(variant_url)
+        }
+    }
+}
+
+/// `Retry-After` is either a number of seconds or an HTTP date.
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    if let Ok(secs) = value.trim().parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+/// Conditional GET, `None` if the server answers that the resource did not change since `validators`.
+async fn get(
+    client: &reqwest::Client,
+    url: Url,
+    validators: &Validators,
+) -> Result<Option<Fetched<Vec<u8>>>, ApiError> {
+    let mut request = client.get(url);
+    if let Some(etag) = &validators.etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = &validators.last_modified {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    let resp = request.send().await?;
+    let status = resp.status();
+    if status == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    if !status.is_success() {
+        let retry_after = parse_retry_after(resp.headers());
+        return Err(match status {
+            StatusCode::NOT_FOUND => ApiError::NotFound,
+            StatusCode::TOO_MANY_REQUESTS => ApiError::RateLimited { retry_after },
+            status if status.is_server_error() => ApiError::ServerError { status, retry_after },
+            status => ApiError::UnexpectedStatus(status),
+        });
+    }
+    let validators = Validators::from_response(&resp);
+    Ok(Some(Fetched {
+        value: resp.bytes().await?.to_vec(),
+        validators,
+    }))
+}
+
+/// Unconditional GET, the server has no reason to answer "not modified".
+async fn get_unconditional(client: &reqwest::Client, url: Url) -> Result<Fetched<Vec<u8>>, ApiError> {
+    get(client, url, &Validators::default())
+        .await?
+        .ok_or(ApiError::UnexpectedStatus(StatusCode::NOT_MODIFIED))
+}
+
+pub async fn fetch_latest(
+    client: &reqwest::Client,
+    base_url: &Url,
+    retry: &RetryPolicy,
+) -> Result<XkcdApiResponse, ApiError> {
     info!("Fetching latest xkcd");
-    let url = XKCD_URL.join("info.0.json")?;
-    let resp = client.get(url).send().await?;
-    let comic: XkcdApiResponse = resp.json().await?;
+    let url = api_url(base_url, &[JSON])?;
+    let body = retry
+        .run("latest xkcd", || get_unconditional(client, url.clone()))
+        .await?;
+    let comic: XkcdApiResponse = serde_json::from_slice(&body.value)?;
     Ok(comic)
 }
 
-pub async fn fetch_xkcd(client: &reqwest::Client, num: u32) -> Result<XkcdApiResponse, anyhow::Error> {
+pub async fn fetch_xkcd(
+    client: &reqwest::Client,
+    base_url: &Url,
+    retry: &RetryPolicy,
+    num: u32,
+) -> Result<Fetched<XkcdApiResponse>, ApiError> {
     info!("Fetching xkcd {}", num);
-    let mut url = XKCD_URL.clone();
-    url.path_segments_mut().unwrap().extend([&num.to_string(), JSON]);
-    let resp = client.get(url).send().await?;
-    let comic: XkcdApiResponse = resp.json().await?;
-    Ok(comic)
+    let url = api_url(base_url, &[&num.to_string(), JSON])?;
+    let body = retry
+        .run(&format!("xkcd {num}"), || get_unconditional(client, url.clone()))
+        .await?;
+    Ok(body.try_map(|body| serde_json::from_slice(&body))?)
+}
+
+/// Like [`fetch_xkcd`], but `None` if the comic did not change since `validators`.
+pub async fn fetch_xkcd_if_modified(
+    client: &reqwest::Client,
+    base_url: &Url,
+    retry: &RetryPolicy,
+    num: u32,
+    validators: &Validators,
+) -> Result<Option<Fetched<XkcdApiResponse>>, ApiError> {
+    info!("Fetching xkcd {} if modified", num);
+    let url = api_url(base_url, &[&num.to_string(), JSON])?;
+    let body = retry
+        .run(&format!("xkcd {num}"), || get(client, url.clone(), validators))
+        .await?;
+    Ok(body
+        .map(|body| body.try_map(|body| serde_json::from_slice(&body)))
+        .transpose()?)
+}
+
+pub async fn fetch_image(
+    client: &reqwest::Client,
+    image_base_url: Option<&Url>,
+    retry: &RetryPolicy,
+    comic: &Xkcd,
+    variant: ImageVariant,
+) -> Result<Fetched<Vec<u8>>, ApiError> {
+    info!("Fetching {:?} image for xkcd {}", variant, comic.num);
+    let url = image_url(comic, image_base_url, variant)?;
+    retry
+        .run(&format!("{variant:?} image for xkcd {}", comic.num), || {
+            get_unconditional(client, url.clone())
+        })
+        .await
+}
+
+/// Like [`fetch_image`], but `None` if the image did not change since `validators`.
+pub async fn fetch_image_if_modified(
+    client: &reqwest::Client,
+    image_base_url: Option<&Url>,
+    retry: &RetryPolicy,
+    comic: &Xkcd,
+    variant: ImageVariant,
+    validators: &Validators,
+) -> Result<Option<Fetched<Vec<u8>>>, ApiError> {
+    info!("Fetching {:?} image for xkcd {} if modified", variant, comic.num);
+    let url = image_url(comic, image_base_url, variant)?;
+    retry
+        .run(&format!("{variant:?} image for xkcd {}", comic.num), || {
+            get(client, url.clone(), validators)
+        })
+        .await
 }
 
-pub async fn fetch_image(client: &reqwest::Client, comic: &Xkcd) -> Result<Vec<u8>, anyhow::Error> {
-    info!("Fetching image for xkcd {}", comic.num);
-    let resp = client.get(comic.image_url.clone()).send().await?;
-    let bytes = resp.bytes().await?.to_vec();
-    Ok(bytes)
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+
+    fn retry_after(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn delay_backs_off_exponentially_up_to_max_delay() {
+        let policy = policy();
+        for _ in 0..100 {
+            assert!(policy.delay(1, None).unwrap() <= Duration::from_millis(100));
+            assert!(policy.delay(3, None).unwrap() <= Duration::from_millis(400));
+            assert!(policy.delay(10, None).unwrap() <= Duration::from_secs(1));
+        }
+        assert!(policy.delay(u32::MAX, None).unwrap() <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn delay_honors_retry_after_up_to_max_delay() {
+        let policy = policy();
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_millis(700))),
+            Some(Duration::from_millis(700))
+        );
+        assert_eq!(policy.delay(1, Some(Duration::from_secs(2))), None);
+    }
+
+    #[test]
+    fn parse_retry_after_seconds() {
+        assert_eq!(parse_retry_after(&retry_after("120")), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(&retry_after(" 5 ")), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn parse_retry_after_date() {
+        let date = (Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let delay = parse_retry_after(&retry_after(&date)).unwrap();
+        assert!(delay > Duration::from_secs(50) && delay <= Duration::from_secs(60));
+        let past = (Utc::now() - chrono::Duration::seconds(60)).to_rfc2822();
+        assert_eq!(parse_retry_after(&retry_after(&past)), None);
+    }
+
+    #[test]
+    fn parse_retry_after_invalid() {
+        assert_eq!(parse_retry_after(&HeaderMap::new()), None);
+        assert_eq!(parse_retry_after(&retry_after("soon")), None);
+        assert_eq!(parse_retry_after(&retry_after("-1")), None);
+    }
+
+    #[test]
+    fn api_url_appends_to_the_base_path() {
+        for base_url in ["https://mirror.local/xkcd", "https://mirror.local/xkcd/"] {
+            let url = api_url(&Url::parse(base_url).unwrap(), &["614", JSON]).unwrap();
+            assert_eq!(url.as_str(), "https://mirror.local/xkcd/614/info.0.json");
+        }
+        let url = api_url(&Url::parse(XKCD_URL).unwrap(), &[JSON]).unwrap();
+        assert_eq!(url.as_str(), "https://xkcd.com/info.0.json");
+    }
+
+    #[test]
+    fn image_url_keeps_encoded_file_names() {
+        let mut comic = Xkcd::sample(1, "Barrel", "");
+        comic.image_url = "https://imgs.xkcd.com/comics/a%20b.png".to_string();
+        let mirror = Url::parse("http://mirror.local:8080/").unwrap();
+
+        let url = image_url(&comic, Some(&mirror), ImageVariant::Standard).unwrap();
+        assert_eq!(url.as_str(), "http://mirror.local:8080/comics/a%20b.png");
+        let url = image_url(&comic, Some(&mirror), ImageVariant::Double).unwrap();
+        assert_eq!(url.as_str(), "http://mirror.local:8080/comics/a%20b_2x.png");
+        let url = image_url(&comic, None, ImageVariant::Double).unwrap();
+        assert_eq!(url.as_str(), "https://imgs.xkcd.com/comics/a%20b_2x.png");
+    }
 }
@@ -1,6 +1,9 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use reqwest::Url;
+
+use crate::{api::XKCD_URL, storage::BackendKind};
 
 #[derive(Debug)]
 #[derive(Parser)]
@@ -8,16 +11,209 @@ use clap::Parser;
 pub struct Cli {
     #[arg(
         long = "db",
+        global = true,
         default_value = "./db.sqlite",
-        help = "Path to the SQLite database file"
+        help = "Path to the SQLite database file, or to the store directory for the dir backend"
     )]
-    #[arg(value_hint = clap::ValueHint::DirPath)]
+    #[arg(value_hint = clap::ValueHint::AnyPath)]
     pub db_path: PathBuf,
-    #[arg(long = "mount", default_value = "./xkcd/", help = "Mount point for the XkcdFS")]
-    #[arg(value_hint = clap::ValueHint::DirPath)]
-    pub mount_point: PathBuf,
+    #[arg(
+        long = "backend",
+        global = true,
+        value_enum,
+        default_value_t = BackendKind::Sqlite,
+        help = "Where fetched comics are stored"
+    )]
+    pub backend: BackendKind,
+    #[arg(long = "base-url", global = true, default_value = XKCD_URL, help = "Base URL of the xkcd API")]
+    #[arg(value_hint = clap::ValueHint::Url)]
+    pub base_url: Url,
+    #[arg(
+        long = "image-base-url",
+        global = true,
+        help = "Fetch images from this host instead of the one in the API response"
+    )]
+    #[arg(value_hint = clap::ValueHint::Url)]
+    pub image_base_url: Option<Url>,
+    #[arg(
+        long = "max-attempts",
+        global = true,
+        default_value_t = 4,
+        help = "How many times to try each request before giving up"
+    )]
+    pub max_attempts: u32,
+    #[arg(
+        long = "offline",
+        global = true,
+        help = "Only use what is already in the database, never touch the network"
+    )]
+    pub offline: bool,
+    #[arg(
+        long = "fetch-2x",
+        global = true,
+        help = "Also fetch the high-resolution _2x images, `sync` adds them to stored comics"
+    )]
+    pub fetch_2x: bool,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug)]
+#[derive(Subcommand)]
+pub enum Command {
+    #[command(about = "Fetch the given range and mount the XkcdFS")]
+    Mount {
+        #[arg(default_value = "./xkcd/", help = "Mount point for the XkcdFS")]
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        mount_point: PathBuf,
+        #[command(flatten)]
+        range: Range,
+        #[arg(
+            long = "update-interval",
+            value_name = "SECONDS",
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "Poll for new comics every SECONDS while mounted"
+        )]
+        update_interval: Option<u64>,
+        #[arg(
+            long = "cache-size",
+            value_name = "MB",
+            default_value_t = 64,
+            help = "Keep up to MB of recently read comics and images in memory, 0 to disable"
+        )]
+        cache_size: usize,
+        #[arg(
+            long = "fetch-on-access",
+            help = "Fetch comics that are not stored yet when they are looked up"
+        )]
+        fetch_on_access: bool,
+    },
+    #[command(about = "Fetch the given range into the database without mounting")]
+    Sync {
+        #[command(flatten)]
+        range: Range,
+    },
+    #[command(about = "Print a single xkcd, fetching it if it is not stored yet")]
+    Get {
+        #[arg(help = "Number of the xkcd")]
+        num: u32,
+        #[arg(
+            long = "field",
+            value_enum,
+            help = "Print only this field (raw bytes for `image` and `image2x`)"
+        )]
+        field: Option<Field>,
+    },
+    #[command(about = "Full-text search over stored titles, alt texts and transcripts")]
+    Search {
+        #[arg(help = "Words to search for")]
+        query: String,
+    },
+    #[command(about = "Show how much of xkcd is stored in the database")]
+    Stats,
+    #[command(about = "Fetch stored comics again and keep what changed upstream")]
+    Refresh {
+        #[arg(long = "start", help = "Only refresh comics from this number on")]
+        start: Option<u32>,
+        #[arg(long = "end", help = "Only refresh comics up to this number")]
+        end: Option<u32>,
+        #[arg(long = "images", help = "Also refresh the stored images")]
+        images: bool,
+    },
+    #[command(about = "Check stored images for truncation and corruption")]
+    Verify {
+        #[arg(long = "repair", help = "Fetch broken images again")]
+        repair: bool,
+    },
+    #[command(about = "Write the whole store to a portable JSON Lines file")]
+    Dump {
+        #[arg(help = "File to write")]
+        #[arg(value_hint = clap::ValueHint::FilePath)]
+        output: PathBuf,
+        #[arg(long = "inline-images", help = "Include the images base64 encoded")]
+        inline_images: bool,
+        #[arg(
+            long = "image-dir",
+            conflicts_with = "inline_images",
+            help = "Write the images into this directory next to the dump"
+        )]
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        image_dir: Option<PathBuf>,
+    },
+    #[command(about = "Merge a JSON Lines dump into the store")]
+    Restore {
+        #[arg(help = "Dump to read")]
+        #[arg(value_hint = clap::ValueHint::FilePath)]
+        input: PathBuf,
+        #[arg(
+            long = "image-dir",
+            help = "Resolve relative image file names against this directory [default: the directory of the dump]"
+        )]
+        #[arg(value_hint = clap::ValueHint::DirPath)]
+        image_dir: Option<PathBuf>,
+    },
+    #[command(about = "Export stored comics to a file, e.g. for a comic reader")]
+    Export {
+        #[arg(long = "format", value_enum, default_value_t = ExportFormat::Cbz, help = "Export format")]
+        format: ExportFormat,
+        #[arg(help = "File to write, or directory for `html`")]
+        #[arg(value_hint = clap::ValueHint::AnyPath)]
+        output: PathBuf,
+        #[arg(long = "start", help = "Only export comics from this number on")]
+        start: Option<u32>,
+        #[arg(long = "end", help = "Only export comics up to this number")]
+        end: Option<u32>,
+        #[arg(long = "year", help = "Only export comics released in this year")]
+        year: Option<i32>,
+    },
+}
+
+#[derive(Debug)]
+#[derive(Args)]
+pub struct Range {
     #[arg(long = "start", default_value_t = u32::MAX, help = "Start of the range to fetch")]
     pub start: u32,
     #[arg(long = "end", default_value_t = u32::MAX, help = "End of the range to fetch")]
     pub end: u32,
 }
+
+#[derive(Debug, Clone, Copy)]
+#[derive(ValueEnum)]
+pub enum ExportFormat {
+    /// Zip of the images with a `ComicInfo.xml`, readable by most comic readers.
+    Cbz,
+    /// Static gallery with an index and one page per comic.
+    Html,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[derive(ValueEnum)]
+pub enum Field {
+    Num,
+    Title,
+    SafeTitle,
+    Alt,
+    Transcript,
+    Link,
+    ImageUrl,
+    ReleaseDate,
+    News,
+    Json,
+    Image,
+    Image2x,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_interval_must_be_positive() {
+        assert!(Cli::try_parse_from(["xkcd", "mount", "--update-interval", "0"]).is_err());
+        let cli = Cli::try_parse_from(["xkcd", "mount", "--update-interval", "60"]).unwrap();
+        assert!(matches!(cli.command, Command::Mount {
+            update_interval: Some(60),
+            ..
+        }));
+    }
+}
@@ -1,11 +1,63 @@
-use chrono::DateTime;
+use anyhow::bail;
+use chrono::{DateTime, Utc};
 use log::info;
-use rusqlite::{Connection, params};
+use rusqlite::{Connection, DatabaseName, OptionalExtension, Transaction, params};
 
-use crate::xkcd::Xkcd;
+use crate::{
+    api::Validators,
+    image::{ImageFormat, ImageVariant},
+    storage::{ImageRecord, Resource, Stats, sha256_hex},
+    xkcd::Xkcd,
+};
 
-pub fn db_init(conn: &Connection) -> rusqlite::Result<()> {
-    conn.execute(
+type Migration = fn(&Transaction) -> rusqlite::Result<()>;
+
+/// Schema changes in the order they were made. A database at `PRAGMA user_version = n` has the first `n` applied.
+///
+/// Databases from before the versioning have `user_version = 0` but may already have any of the changes, so the
+/// migrations check for the columns they add instead of relying on the version alone.
+const MIGRATIONS: &[(&str, Migration)] = &[
+    ("create xkcds and images", create_tables),
+    ("add xkcds.news", add_news),
+    ("add images.mime_type", add_mime_type),
+    ("add images.variant", add_image_variant),
+    ("create gaps", create_gaps),
+    ("create full-text index", create_fts),
+    ("add images.sha256", add_image_sha256),
+    ("add hashes and cache validators", add_validators),
+    ("create missing_2x", create_missing_2x),
+];
+
+pub fn db_init(conn: &mut Connection) -> anyhow::Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        bail!(
+            "database schema version {version} is newer than the supported version {}, please upgrade",
+            MIGRATIONS.len()
+        );
+    }
+
+    for (i, (name, migration)) in MIGRATIONS.iter().enumerate().skip(version) {
+        info!("Migrating database to version {}: {name}", i + 1);
+        let tx = conn.transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "select count(*) from pragma_table_info(?1) where name = ?2",
+        params![table, column],
+        |row| row.get::<_, u32>(0),
+    )
+    .map(|count| count > 0)
+}
+
+fn create_tables(conn: &Transaction) -> rusqlite::Result<()> {
+    conn.execute_batch(
         r#"
         create table if not exists xkcds (
             num integer primary key,
@@ -16,30 +68,157 @@ pub fn db_init(conn: &Connection) -> rusqlite::Result<()> {
             transcript text,
             link text,
             release_date integer not null
-        )"#,
-        [],
-    )?;
+        );
 
-    conn.execute(
-        r#"
         create table if not exists images (
             num integer primary key,
             image_size integer not null,
             image_data blob not null,
             foreign key (num) references xkcds (num)
+        );
+        "#,
+    )
+}
+
+fn add_news(conn: &Transaction) -> rusqlite::Result<()> {
+    if !has_column(conn, "xkcds", "news")? {
+        conn.execute("alter table xkcds add column news text", [])?;
+    }
+    Ok(())
+}
+
+fn add_mime_type(conn: &Transaction) -> rusqlite::Result<()> {
+    if !has_column(conn, "images", "mime_type")? {
+        conn.execute("alter table images add column mime_type text", [])?;
+    }
+    Ok(())
+}
+
+/// The primary key changes to `(num, variant)`, which needs the table to be rebuilt.
+fn add_image_variant(conn: &Transaction) -> rusqlite::Result<()> {
+    if has_column(conn, "images", "variant")? {
+        return Ok(());
+    }
+    conn.execute_batch(
+        r#"
+        create table images_new (
+            num integer not null,
+            variant integer not null default 1,
+            image_size integer not null,
+            image_data blob not null,
+            mime_type text,
+            primary key (num, variant),
+            foreign key (num) references xkcds (num)
+        );
+
+        insert into images_new (num, variant, image_size, image_data, mime_type)
+        select num, 1, image_size, image_data, mime_type from images;
+
+        drop table images;
+        alter table images_new rename to images;
+        "#,
+    )
+}
+
+fn create_gaps(conn: &Transaction) -> rusqlite::Result<()> {
+    conn.execute(
+        r#"
+        create table if not exists gaps (
+            num integer primary key,
+            checked_at integer not null
         )"#,
         [],
     )?;
+    Ok(())
+}
+
+fn create_fts(conn: &Transaction) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        r#"
+        create virtual table if not exists xkcds_fts using fts5 (title, alt, transcript);
+
+        create trigger if not exists xkcds_fts_insert after insert on xkcds begin
+            insert into xkcds_fts (rowid, title, alt, transcript)
+            values (new.num, new.title, new.alt, new.transcript);
+        end;
 
+        create trigger if not exists xkcds_fts_update after update on xkcds begin
+            delete from xkcds_fts where rowid = old.num;
+            insert into xkcds_fts (rowid, title, alt, transcript)
+            values (new.num, new.title, new.alt, new.transcript);
+        end;
+
+        create trigger if not exists xkcds_fts_delete after delete on xkcds begin
+            delete from xkcds_fts where rowid = old.num;
+        end;
+
+        insert into xkcds_fts (rowid, title, alt, transcript)
+        select num, title, alt, transcript from xkcds
+        where num not in (select rowid from xkcds_fts);
+        "#,
+    )
+}
+
+fn add_image_sha256(conn: &Transaction) -> rusqlite::Result<()> {
+    if !has_column(conn, "images", "sha256")? {
+        conn.execute("alter table images add column sha256 text", [])?;
+    }
+    Ok(())
+}
+
+fn add_validators(conn: &Transaction) -> rusqlite::Result<()> {
+    for (table, column) in [
+        ("xkcds", "sha256 text"),
+        ("xkcds", "changed_at integer"),
+        ("xkcds", "etag text"),
+        ("xkcds", "last_modified text"),
+        ("images", "etag text"),
+        ("images", "last_modified text"),
+    ] {
+        let name = column.split_whitespace().next().unwrap();
+        if !has_column(conn, table, name)? {
+            conn.execute(&format!("alter table {table} add column {column}"), [])?;
+        }
+    }
+    Ok(())
+}
+
+/// Comics whose `_2x` image does not exist upstream, so it is not requested again.
+fn create_missing_2x(conn: &Transaction) -> rusqlite::Result<()> {
+    conn.execute(
+        r#"
+        create table if not exists missing_2x (
+            num integer primary key,
+            checked_at integer not null
+        )"#,
+        [],
+    )?;
     Ok(())
 }
-pub fn insert_meta(conn: &Connection, xkcd: &Xkcd) -> rusqlite::Result<()> {
+
+/// Upserts the comic and returns whether it is new or changed. `changed_at` is only bumped on actual changes, so a
+/// refresh that gets the same data back leaves it alone.
+pub fn insert_meta(conn: &Connection, xkcd: &Xkcd) -> anyhow::Result<bool> {
     info!("Inserting xkcd {}", xkcd);
+    let json = xkcd.to_json();
+    let sha256 = sha256_hex(json.as_bytes());
+    let stored_sha256: Option<Option<String>> = conn
+        .query_row(r#"SELECT sha256 FROM xkcds WHERE num = ?1"#, params![xkcd.num], |row| {
+            row.get(0)
+        })
+        .optional()?;
+    let changed = match stored_sha256 {
+        None => true,
+        Some(Some(stored_sha256)) => stored_sha256 != sha256,
+        // Stored before hashes were recorded.
+        Some(None) => get_meta(conn, xkcd.num)?.is_none_or(|stored| stored.to_json() != json),
+    };
+
     let release_date = xkcd.release_date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp();
     conn.execute(
         r#"
-        INSERT INTO xkcds (num, title, safe_title, image_url, alt, transcript, link, release_date)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+        INSERT INTO xkcds (num, title, safe_title, image_url, alt, transcript, link, release_date, news, sha256, changed_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
         ON CONFLICT(num) DO UPDATE SET
             title = excluded.title,
             safe_title = excluded.safe_title,
@@ -47,7 +226,10 @@ pub fn insert_meta(conn: &Connection, xkcd: &Xkcd) -> rusqlite::Result<()> {
             alt = excluded.alt,
             transcript = excluded.transcript,
             link = excluded.link,
-            release_date = excluded.release_date;
+            release_date = excluded.release_date,
+            news = excluded.news,
+            sha256 = excluded.sha256,
+            changed_at = coalesce(excluded.changed_at, xkcds.changed_at);
         "#,
         params![
             xkcd.num,
@@ -58,64 +240,222 @@ pub fn insert_meta(conn: &Connection, xkcd: &Xkcd) -> rusqlite::Result<()> {
             xkcd.transcript,
             xkcd.link,
             release_date,
+            xkcd.news,
+            sha256,
+            changed.then(|| Utc::now().timestamp()),
         ],
     )?;
-    Ok(())
+    Ok(changed)
 }
 
-pub fn insert_image(conn: &Connection, num: u32, image_data: &[u8]) -> rusqlite::Result<()> {
-    info!("Inserting image for xkcd {}", num);
+pub fn insert_image(
+    conn: &Connection,
+    num: u32,
+    variant: ImageVariant,
+    image_data: &[u8],
+    format: ImageFormat,
+) -> rusqlite::Result<()> {
+    info!("Inserting {:?} image for xkcd {}", variant, num);
+    let sha256 = sha256_hex(image_data);
+    let stored_sha256: Option<Option<String>> = conn
+        .query_row(
+            r#"SELECT sha256 FROM images WHERE num = ?1 AND variant = ?2"#,
+            params![num, variant.scale()],
+            |row| row.get(0),
+        )
+        .optional()?;
     conn.execute(
         r#"
-        INSERT INTO images (num, image_data, image_size)
-        VALUES (?1, ?2, length(?2))
-        ON CONFLICT(num) DO UPDATE SET
-            image_data = excluded.image_data;
-            image_size = legth(excluded.image_size);
+        INSERT INTO images (num, variant, image_data, image_size, mime_type, sha256)
+        VALUES (?1, ?2, ?3, length(?3), ?4, ?5)
+        ON CONFLICT(num, variant) DO UPDATE SET
+            image_data = excluded.image_data,
+            image_size = excluded.image_size,
+            mime_type = excluded.mime_type,
+            sha256 = excluded.sha256;
         "#,
-        params![num, image_data],
+        params![num, variant.scale(), image_data, format.mime_type(), sha256],
     )?;
+    // A changed image is a change of the comic, its size in the mounted filesystem changes.
+    if stored_sha256.is_some_and(|stored_sha256| stored_sha256 != Some(sha256)) {
+        conn.execute(r#"UPDATE xkcds SET changed_at = ?2 WHERE num = ?1"#, params![
+            num,
+            Utc::now().timestamp()
+        ])?;
+    }
     Ok(())
 }
 
-pub fn get_meta(conn: &Connection, num: u32) -> anyhow::Result<Xkcd> {
+pub fn get_meta(conn: &Connection, num: u32) -> anyhow::Result<Option<Xkcd>> {
     info!("Loading from DB xkcd {}", num);
     let mut stmt = conn.prepare(
-        r#"SELECT num, title, safe_title, image_url, alt, transcript, link, release_date FROM xkcds WHERE num = ?1"#,
+        r#"SELECT num, title, safe_title, image_url, alt, transcript, link, release_date, news FROM xkcds WHERE num = ?1"#,
     )?;
 
-    let xkcd = stmt.query_row(params![num], |row| {
-        let release_date = row.get::<_, i64>(7)?;
-        let release_date = DateTime::from_timestamp(release_date, 0).unwrap().date_naive();
-        Ok(Xkcd {
-            num: row.get(0)?,
-            title: row.get(1)?,
-            safe_title: row.get(2)?,
-            image_url: row.get(3)?,
-            alt: row.get(4)?,
-            transcript: row.get(5)?,
-            link: row.get(6)?,
-            release_date,
+    let xkcd = stmt
+        .query_row(params![num], |row| {
+            let release_date = row.get::<_, i64>(7)?;
+            let release_date = DateTime::from_timestamp(release_date, 0).unwrap().date_naive();
+            Ok(Xkcd {
+                num: row.get(0)?,
+                title: row.get(1)?,
+                safe_title: row.get(2)?,
+                image_url: row.get(3)?,
+                alt: row.get(4)?,
+                transcript: row.get(5)?,
+                link: row.get(6)?,
+                release_date,
+                news: row.get::<_, Option<String>>(8)?.unwrap_or_default(),
+            })
         })
-    })?;
+        .optional()?;
 
     Ok(xkcd)
 }
 
-pub fn get_image(conn: &Connection, num: u32) -> anyhow::Result<Vec<u8>> {
-    info!("Loading from DB image for xkcd {}", num);
-    let mut stmt = conn.prepare(r#"SELECT image_data FROM images wherE num = ?1"#)?;
-    let image_data = stmt.query_row(params![num], |row| row.get::<_, Vec<u8>>(0))?;
+pub fn get_image(conn: &Connection, num: u32, variant: ImageVariant) -> anyhow::Result<Option<Vec<u8>>> {
+    info!("Loading from DB {:?} image for xkcd {}", variant, num);
+    let mut stmt = conn.prepare(r#"SELECT image_data FROM images WHERE num = ?1 AND variant = ?2"#)?;
+    let image_data = stmt
+        .query_row(params![num, variant.scale()], |row| row.get::<_, Vec<u8>>(0))
+        .optional()?;
     Ok(image_data)
 }
 
-pub fn get_image_size(conn: &Connection, num: u32) -> anyhow::Result<u64> {
-    info!("Loading from DB image size for xkcd {}", num);
-    let mut stmt = conn.prepare(r#"SELECT image_size FROM images WHERE num = ?1"#)?;
-    let image_size = stmt.query_row(params![num], |row| row.get::<_, u64>(0))?;
+/// Up to `len` bytes of the image starting at `offset`, read through the incremental BLOB API so the rest of the
+/// image is never loaded.
+pub fn read_image(
+    conn: &Connection,
+    num: u32,
+    variant: ImageVariant,
+    offset: u64,
+    len: usize,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    info!(
+        "Reading from DB {:?} image for xkcd {} at {}+{}",
+        variant, num, offset, len
+    );
+    let rowid = conn
+        .query_row(
+            r#"SELECT rowid FROM images WHERE num = ?1 AND variant = ?2"#,
+            params![num, variant.scale()],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?;
+    let Some(rowid) = rowid else {
+        return Ok(None);
+    };
+    let blob = conn.blob_open(DatabaseName::Main, "images", "image_data", rowid, true)?;
+    let start = usize::try_from(offset).unwrap_or(usize::MAX).min(blob.len());
+    let mut data = vec![0; len.min(blob.len() - start)];
+    let read = blob.read_at(&mut data, start)?;
+    data.truncate(read);
+    Ok(Some(data))
+}
+
+pub fn get_image_size(conn: &Connection, num: u32, variant: ImageVariant) -> anyhow::Result<Option<u64>> {
+    info!("Loading from DB {:?} image size for xkcd {}", variant, num);
+    let mut stmt = conn.prepare(r#"SELECT image_size FROM images WHERE num = ?1 AND variant = ?2"#)?;
+    let image_size = stmt
+        .query_row(params![num, variant.scale()], |row| row.get::<_, u64>(0))
+        .optional()?;
     Ok(image_size)
 }
 
+/// Stored MIME type of the image, sniffed from its first bytes for images stored without one.
+pub fn get_image_format(conn: &Connection, num: u32, variant: ImageVariant) -> anyhow::Result<Option<ImageFormat>> {
+    info!("Loading from DB {:?} image format for xkcd {}", variant, num);
+    let mut stmt =
+        conn.prepare(r#"SELECT mime_type, substr(image_data, 1, 16) FROM images WHERE num = ?1 AND variant = ?2"#)?;
+    let row = stmt
+        .query_row(params![num, variant.scale()], |row| {
+            Ok((row.get::<_, Option<String>>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })
+        .optional()?;
+    let format = row.and_then(|(mime_type, header)| {
+        mime_type
+            .and_then(|mime_type| ImageFormat::from_mime_type(&mime_type))
+            .or_else(|| ImageFormat::sniff(&header))
+    });
+    Ok(format)
+}
+
+/// The image together with the size, MIME type and hash recorded when it was inserted.
+pub fn get_image_record(conn: &Connection, num: u32, variant: ImageVariant) -> anyhow::Result<Option<ImageRecord>> {
+    info!("Loading from DB {:?} image record for xkcd {}", variant, num);
+    let mut stmt = conn
+        .prepare(r#"SELECT image_data, image_size, mime_type, sha256 FROM images WHERE num = ?1 AND variant = ?2"#)?;
+    let record = stmt
+        .query_row(params![num, variant.scale()], |row| {
+            Ok(ImageRecord {
+                data: row.get(0)?,
+                size: row.get(1)?,
+                format: row
+                    .get::<_, Option<String>>(2)?
+                    .and_then(|mime_type| ImageFormat::from_mime_type(&mime_type)),
+                sha256: row.get(3)?,
+            })
+        })
+        .optional()?;
+    Ok(record)
+}
+
+pub fn get_validators(conn: &Connection, num: u32, resource: Resource) -> anyhow::Result<Option<Validators>> {
+    info!("Loading from DB validators of {:?} for xkcd {}", resource, num);
+    let to_validators = |row: &rusqlite::Row| {
+        Ok(Validators {
+            etag: row.get(0)?,
+            last_modified: row.get(1)?,
+        })
+    };
+    let validators = match resource {
+        Resource::Meta => conn
+            .query_row(
+                r#"SELECT etag, last_modified FROM xkcds WHERE num = ?1"#,
+                params![num],
+                to_validators,
+            )
+            .optional()?,
+        Resource::Image(variant) => conn
+            .query_row(
+                r#"SELECT etag, last_modified FROM images WHERE num = ?1 AND variant = ?2"#,
+                params![num, variant.scale()],
+                to_validators,
+            )
+            .optional()?,
+    };
+    Ok(validators.filter(|validators| *validators != Validators::default()))
+}
+
+pub fn set_validators(
+    conn: &Connection,
+    num: u32,
+    resource: Resource,
+    validators: &Validators,
+) -> rusqlite::Result<()> {
+    info!("Storing validators of {:?} for xkcd {}", resource, num);
+    match resource {
+        Resource::Meta => conn.execute(
+            r#"UPDATE xkcds SET etag = ?2, last_modified = ?3 WHERE num = ?1"#,
+            params![num, validators.etag, validators.last_modified],
+        )?,
+        Resource::Image(variant) => conn.execute(
+            r#"UPDATE images SET etag = ?3, last_modified = ?4 WHERE num = ?1 AND variant = ?2"#,
+            params![num, variant.scale(), validators.etag, validators.last_modified],
+        )?,
+    };
+    Ok(())
+}
+
+/// Comics that were stored or changed at or after the unix timestamp `since`.
+pub fn get_changed_since(conn: &Connection, since: i64) -> anyhow::Result<Vec<u32>> {
+    info!("Loading from DB xkcds changed since {}", since);
+    let mut stmt = conn.prepare(r#"SELECT num FROM xkcds WHERE changed_at >= ?1 ORDER BY num"#)?;
+    let ids = stmt.query_map(params![since], |row| row.get(0))?;
+    let ids: Vec<u32> = ids.collect::<Result<_, _>>()?;
+    Ok(ids)
+}
+
 pub fn get_stored_ids(conn: &Connection) -> anyhow::Result<Vec<u32>> {
     info!("Loading from DB all xkcd ids");
     let mut stmt = conn.prepare(r#"SELECT num FROM xkcds"#)?;
@@ -123,3 +463,294 @@ pub fn get_stored_ids(conn: &Connection) -> anyhow::Result<Vec<u32>> {
     let ids: Vec<u32> = ids.collect::<Result<_, _>>()?;
     Ok(ids)
 }
+
+/// Full-text search over titles, alt texts and transcripts, best matches first.
+///
+/// Every word of the query has to be present; FTS5 query syntax is not interpreted.
+pub fn search(conn: &Connection, query: &str) -> anyhow::Result<Vec<u32>> {
+    info!("Searching DB for {:?}", query);
+    let query = query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ");
+    if query.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut stmt = conn.prepare(r#"SELECT rowid FROM xkcds_fts WHERE xkcds_fts MATCH ?1 ORDER BY rank"#)?;
+    let ids = stmt.query_map(params![query], |row| row.get(0))?;
+    let ids: Vec<u32> = ids.collect::<Result<_, _>>()?;
+    Ok(ids)
+}
+
+pub fn get_stats(conn: &Connection) -> anyhow::Result<Stats> {
+    info!("Loading from DB stats");
+    let stats = conn.query_row(
+        r#"SELECT count(*), (SELECT count(*) FROM images WHERE variant = 1), min(num), max(num) FROM xkcds"#,
+        [],
+        |row| {
+            Ok(Stats {
+                comics: row.get(0)?,
+                images: row.get(1)?,
+                first: row.get(2)?,
+                last: row.get(3)?,
+                gaps: vec![],
+            })
+        },
+    )?;
+    Ok(Stats {
+        gaps: get_gaps(conn)?,
+        ..stats
+    })
+}
+
+/// Records a comic number that does not exist upstream, like #404.
+pub fn insert_gap(conn: &Connection, num: u32) -> rusqlite::Result<()> {
+    info!("Inserting gap {}", num);
+    conn.execute(
+        r#"
+        INSERT INTO gaps (num, checked_at)
+        VALUES (?1, ?2)
+        ON CONFLICT(num) DO UPDATE SET
+            checked_at = excluded.checked_at;
+        "#,
+        params![num, Utc::now().timestamp()],
+    )?;
+    Ok(())
+}
+
+pub fn get_gaps(conn: &Connection) -> anyhow::Result<Vec<u32>> {
+    info!("Loading from DB all gaps");
+    let mut stmt = conn.prepare(r#"SELECT num FROM gaps ORDER BY num"#)?;
+    let ids = stmt.query_map([], |row| row.get(0))?;
+    let ids: Vec<u32> = ids.collect::<Result<_, _>>()?;
+    Ok(ids)
+}
+
+pub fn insert_missing_2x(conn: &Connection, num: u32) -> rusqlite::Result<()> {
+    info!("Inserting missing 2x image {}", num);
+    conn.execute(
+        r#"
+        INSERT INTO missing_2x (num, checked_at)
+        VALUES (?1, ?2)
+        ON CONFLICT(num) DO UPDATE SET
+            checked_at = excluded.checked_at;
+        "#,
+        params![num, Utc::now().timestamp()],
+    )?;
+    Ok(())
+}
+
+pub fn get_missing_2x(conn: &Connection) -> anyhow::Result<Vec<u32>> {
+    info!("Loading from DB all missing 2x images");
+    let mut stmt = conn.prepare(r#"SELECT num FROM missing_2x ORDER BY num"#)?;
+    let ids = stmt.query_map([], |row| row.get(0))?;
+    let ids: Vec<u32> = ids.collect::<Result<_, _>>()?;
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        db_init(&mut conn).unwrap();
+        conn
+    }
+
+    /// A database from before the schema was versioned, with the first `migrations` changes made by hand.
+    fn unversioned(migrations: usize) -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let tx = conn.transaction().unwrap();
+        for (_, migration) in &MIGRATIONS[..migrations] {
+            migration(&tx).unwrap();
+        }
+        tx.commit().unwrap();
+        conn
+    }
+
+    fn user_version(conn: &Connection) -> usize {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn new_database_gets_every_migration() {
+        let mut conn = open();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+        for (table, column) in [
+            ("xkcds", "news"),
+            ("images", "variant"),
+            ("images", "sha256"),
+            ("xkcds", "etag"),
+            ("missing_2x", "num"),
+        ] {
+            assert!(has_column(&conn, table, column).unwrap(), "{table}.{column}");
+        }
+        db_init(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn unversioned_database_with_every_change_is_migrated() {
+        let mut conn = unversioned(MIGRATIONS.len());
+        insert_meta(&conn, &Xkcd::sample(1, "Barrel", "")).unwrap();
+        insert_image(&conn, 1, ImageVariant::Standard, b"GIF89a", ImageFormat::Gif).unwrap();
+        db_init(&mut conn).unwrap();
+
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+        assert_eq!(get_stored_ids(&conn).unwrap(), vec![1]);
+        assert_eq!(search(&conn, "barrel").unwrap(), vec![1]);
+        assert_eq!(
+            get_image(&conn, 1, ImageVariant::Standard).unwrap(),
+            Some(b"GIF89a".to_vec())
+        );
+    }
+
+    #[test]
+    fn comics_stored_before_the_index_are_searchable() {
+        let mut conn = unversioned(5);
+        conn.execute(
+            "insert into xkcds (num, title, safe_title, image_url, alt, release_date)
+             values (1, 'Barrel', 'Barrel', 'barrel.jpg', '', 1136073600)",
+            [],
+        )
+        .unwrap();
+        db_init(&mut conn).unwrap();
+        assert_eq!(search(&conn, "barrel").unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let mut conn = open();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+        assert!(db_init(&mut conn).is_err());
+    }
+
+    #[test]
+    fn news_is_added_to_existing_databases() {
+        let mut conn = unversioned(1);
+        conn.execute(
+            "insert into xkcds (num, title, safe_title, image_url, alt, transcript, link, release_date)
+             values (1, 'Barrel', 'Barrel', 'barrel.jpg', 'Don''t we all.', '', '', 1136073600)",
+            [],
+        )
+        .unwrap();
+        db_init(&mut conn).unwrap();
+
+        assert_eq!(get_meta(&conn, 1).unwrap().unwrap().news, "");
+        let mut xkcd = Xkcd::sample(2, "Regex", "");
+        xkcd.news = "New book!".to_string();
+        insert_meta(&conn, &xkcd).unwrap();
+        assert_eq!(get_meta(&conn, 2).unwrap().unwrap().news, "New book!");
+    }
+
+    #[test]
+    fn mime_type_is_added_to_existing_databases() {
+        let mut conn = unversioned(2);
+        conn.execute_batch(
+            "insert into xkcds (num, title, safe_title, image_url, alt, release_date)
+             values (1, 'Barrel', 'Barrel', 'barrel.jpg', '', 1136073600);
+             insert into images (num, image_size, image_data) values (1, 8, x'ffd8ffe000104a46');",
+        )
+        .unwrap();
+        db_init(&mut conn).unwrap();
+
+        // Stored without a MIME type, so it is sniffed.
+        assert_eq!(
+            get_image_format(&conn, 1, ImageVariant::Standard).unwrap(),
+            Some(ImageFormat::Jpeg)
+        );
+        insert_image(&conn, 1, ImageVariant::Standard, b"GIF89a", ImageFormat::Gif).unwrap();
+        assert_eq!(
+            get_image_format(&conn, 1, ImageVariant::Standard).unwrap(),
+            Some(ImageFormat::Gif)
+        );
+    }
+
+    #[test]
+    fn variant_is_added_to_existing_databases() {
+        let mut conn = unversioned(3);
+        conn.execute_batch(
+            "insert into xkcds (num, title, safe_title, image_url, alt, release_date)
+             values (1, 'Barrel', 'Barrel', 'barrel.jpg', '', 1136073600);
+             insert into images (num, image_size, image_data, mime_type) values (1, 3, x'010203', 'image/png');",
+        )
+        .unwrap();
+        db_init(&mut conn).unwrap();
+
+        assert_eq!(
+            get_image(&conn, 1, ImageVariant::Standard).unwrap(),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(get_image(&conn, 1, ImageVariant::Double).unwrap(), None);
+        insert_image(&conn, 1, ImageVariant::Double, &[4, 5], ImageFormat::Png).unwrap();
+        insert_image(&conn, 1, ImageVariant::Standard, &[6], ImageFormat::Png).unwrap();
+        assert_eq!(get_image(&conn, 1, ImageVariant::Standard).unwrap(), Some(vec![6]));
+        assert_eq!(get_image(&conn, 1, ImageVariant::Double).unwrap(), Some(vec![4, 5]));
+        assert_eq!(get_image_size(&conn, 1, ImageVariant::Double).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn changed_images_mark_the_comic_changed() {
+        let conn = open();
+        insert_meta(&conn, &Xkcd::sample(1, "Barrel", "")).unwrap();
+        insert_image(&conn, 1, ImageVariant::Standard, b"GIF89a", ImageFormat::Gif).unwrap();
+        conn.execute("update xkcds set changed_at = 0", []).unwrap();
+        let since = Utc::now().timestamp();
+
+        insert_image(&conn, 1, ImageVariant::Standard, b"GIF89a", ImageFormat::Gif).unwrap();
+        assert_eq!(get_changed_since(&conn, since).unwrap(), Vec::<u32>::new());
+        insert_image(&conn, 1, ImageVariant::Standard, b"GIF89a;", ImageFormat::Gif).unwrap();
+        assert_eq!(get_changed_since(&conn, since).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn search_matches_all_words() {
+        let conn = open();
+        insert_meta(&conn, &Xkcd::sample(1, "Barrel", "Don't we all.")).unwrap();
+        insert_meta(
+            &conn,
+            &Xkcd::sample(2, "Regex", "Regular expressions, regex everywhere"),
+        )
+        .unwrap();
+        insert_meta(&conn, &Xkcd::sample(3, "Perl", "regular expressions")).unwrap();
+
+        let mut found = search(&conn, "regular expressions").unwrap();
+        found.sort();
+        assert_eq!(found, vec![2, 3]);
+        assert_eq!(search(&conn, "regex").unwrap(), vec![2]);
+        assert_eq!(search(&conn, "regex perl").unwrap(), Vec::<u32>::new());
+        assert_eq!(search(&conn, "   ").unwrap(), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn search_does_not_interpret_fts_syntax() {
+        let conn = open();
+        insert_meta(&conn, &Xkcd::sample(1, "Barrel", "Don't we all.")).unwrap();
+        insert_meta(
+            &conn,
+            &Xkcd::sample(2, "Exploits of a Mom", "Her daughter is named \"Help I'm trapped\""),
+        )
+        .unwrap();
+
+        for query in [
+            "\"",
+            "a OR b",
+            "NOT",
+            "title:barrel",
+            "barrel*",
+            "(barrel",
+            "-barrel",
+            "^barrel",
+            "don't",
+        ] {
+            assert!(search(&conn, query).is_ok(), "{query}");
+        }
+        assert_eq!(search(&conn, "barrel OR mom").unwrap(), Vec::<u32>::new());
+        assert_eq!(search(&conn, "title:barrel").unwrap(), Vec::<u32>::new());
+        assert_eq!(search(&conn, "\"help").unwrap(), vec![2]);
+        assert_eq!(search(&conn, "don't").unwrap(), vec![1]);
+    }
+}
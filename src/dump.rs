@@ -0,0 +1,145 @@
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, bail};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    image::{ImageFormat, ImageVariant},
+    storage::{BlockingXkcdStorage, Storage},
+    xkcd::Xkcd,
+};
+
+/// Where `dump` puts the images.
+#[derive(Debug)]
+pub enum DumpImages<'a> {
+    None,
+    /// Base64 encoded in the `data` field of each image.
+    Inline,
+    /// As files in a sidecar directory, referenced by the `file` field.
+    Dir(&'a Path),
+}
+
+/// One line of a dump: the `xkcds` row plus its images.
+#[derive(Debug, Deserialize, Serialize)]
+struct Entry {
+    #[serde(flatten)]
+    meta: Xkcd,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    images: Vec<Image>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Image {
+    /// 1 for the standard image, 2 for `_2x`.
+    scale: u32,
+    mime_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+}
+
+fn variant(scale: u32) -> Option<ImageVariant> {
+    [ImageVariant::Standard, ImageVariant::Double]
+        .into_iter()
+        .find(|variant| variant.scale() == scale)
+}
+
+/// Writes every stored comic as one JSON object per line. Returns the number of comics written.
+pub fn dump(storage: &BlockingXkcdStorage, path: &Path, images: DumpImages) -> anyhow::Result<usize> {
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    // Image files are referenced relative to the dump when they are next to it, so both can be moved together.
+    let image_prefix = match images {
+        DumpImages::Dir(dir) => {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+            let dir = dir.canonicalize()?;
+            // The parent of a bare file name is empty, not `.`.
+            let base = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+                .canonicalize()?;
+            dir.strip_prefix(&base).map(Path::to_path_buf).unwrap_or(dir)
+        }
+        _ => PathBuf::new(),
+    };
+
+    let mut ids = storage.get_stored_ids()?;
+    ids.sort_unstable();
+    for &num in &ids {
+        let meta = storage.get_meta(num)?;
+        let mut entry = Entry { meta, images: vec![] };
+        for variant in [ImageVariant::Standard, ImageVariant::Double] {
+            let (DumpImages::Inline | DumpImages::Dir(_)) = images else {
+                break;
+            };
+            // Only dump what is stored, `get_image` would go and fetch missing images.
+            let Ok(format) = storage.get_image_format(num, variant) else {
+                continue;
+            };
+            let data = storage.get_image(num, variant)?;
+            let mut image = Image {
+                scale: variant.scale(),
+                mime_type: format.mime_type().to_string(),
+                data: None,
+                file: None,
+            };
+            match images {
+                DumpImages::Inline => image.data = Some(BASE64.encode(&data)),
+                DumpImages::Dir(dir) => {
+                    let name = variant.file_name(&format!("xkcd_{num}.{}", format.extension()));
+                    fs::write(dir.join(&name), &data)?;
+                    image.file = Some(image_prefix.join(name).to_string_lossy().into_owned());
+                }
+                DumpImages::None => unreachable!(),
+            }
+            entry.images.push(image);
+        }
+        serde_json::to_writer(&mut writer, &entry)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(ids.len())
+}
+
+/// Merges a dump into the store, overwriting comics and images that are already stored. Relative image file names
+/// are looked up in `image_dir`. Returns the number of comics restored.
+pub fn restore(storage: &BlockingXkcdStorage, path: &Path, image_dir: &Path) -> anyhow::Result<usize> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut restored = 0;
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = serde_json::from_str(&line).with_context(|| format!("Invalid entry on line {}", i + 1))?;
+        let num = entry.meta.num;
+        info!("Restoring xkcd {num}");
+        storage.insert_meta(&entry.meta)?;
+        for image in entry.images {
+            let Some(variant) = variant(image.scale) else {
+                bail!("Invalid image scale {} for xkcd {num}", image.scale);
+            };
+            let data = match (image.data, image.file) {
+                (Some(data), _) => BASE64
+                    .decode(data)
+                    .with_context(|| format!("Invalid image data for xkcd {num}"))?,
+                (None, Some(file)) => fs::read(image_dir.join(&file))
+                    .with_context(|| format!("Failed to read {}", image_dir.join(&file).display()))?,
+                (None, None) => bail!("Image of xkcd {num} has neither data nor file"),
+            };
+            let format = ImageFormat::from_mime_type(&image.mime_type)
+                .unwrap_or_else(|| ImageFormat::detect(&data, &entry.meta.image_url));
+            storage.insert_image(num, variant, &data, format)?;
+        }
+        restored += 1;
+    }
+    Ok(restored)
+}
@@ -0,0 +1,123 @@
+use std::{fmt::Write as _, fs::File, io::Write, path::Path};
+
+use anyhow::Context;
+use log::info;
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    export::{escape_xml, stored_image},
+    storage::Storage,
+    xkcd::Xkcd,
+};
+
+/// Writes the images of `comics` into a CBZ archive at `path`, one page per comic, followed by a `ComicInfo.xml`.
+/// Returns the number of pages written.
+pub fn export(storage: &impl Storage, comics: &[Xkcd], path: &Path) -> anyhow::Result<usize> {
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut zip = ZipWriter::new(file);
+    // Images are already compressed, deflating them again only costs time.
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    // Readers order pages by file name, so pad the numbers to a common width.
+    let width = comics.iter().map(|meta| meta.num.to_string().len()).max().unwrap_or(1);
+
+    let mut pages = vec![];
+    for meta in comics {
+        let Some((image, format)) = stored_image(storage, meta.num) else {
+            continue;
+        };
+        let name = format!("{:0width$}.{}", meta.num, format.extension());
+        info!("Adding {name}");
+        zip.start_file(name, options)?;
+        zip.write_all(&image)?;
+        pages.push(meta);
+    }
+
+    zip.start_file("ComicInfo.xml", options)?;
+    zip.write_all(comic_info(&pages).as_bytes())?;
+    zip.finish()?;
+    Ok(pages.len())
+}
+
+fn comic_info(pages: &[&Xkcd]) -> String {
+    let summary = pages
+        .iter()
+        .map(|meta| format!("#{} {}: {}", meta.num, meta.title, meta.alt))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    xml.push_str("<ComicInfo xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\">\n");
+    xml.push_str("  <Series>xkcd</Series>\n");
+    if let (Some(first), Some(last)) = (pages.first(), pages.last()) {
+        let _ = writeln!(xml, "  <Title>xkcd {}-{}</Title>", first.num, last.num);
+        let _ = writeln!(xml, "  <Year>{}</Year>", first.release_date.format("%Y"));
+    }
+    let _ = writeln!(xml, "  <Summary>{}</Summary>", escape_xml(&summary));
+    xml.push_str("  <Writer>Randall Munroe</Writer>\n");
+    xml.push_str("  <Web>https://xkcd.com/</Web>\n");
+    let _ = writeln!(xml, "  <PageCount>{}</PageCount>", pages.len());
+    xml.push_str("  <Pages>\n");
+    for (i, meta) in pages.iter().enumerate() {
+        let _ = writeln!(
+            xml,
+            "    <Page Image=\"{i}\" Bookmark=\"{}\" />",
+            escape_xml(&format!("{}: {}", meta.num, meta.title))
+        );
+    }
+    xml.push_str("  </Pages>\n");
+    xml.push_str("</ComicInfo>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read, process};
+
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::{
+        image::{ImageFormat, ImageVariant},
+        storage::BlockingXkcdStorage,
+    };
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\0IEND\xaeB`\x82";
+
+    #[test]
+    fn pages_in_comic_order_with_escaped_comic_info() {
+        let storage = BlockingXkcdStorage::memory();
+        let comics = [
+            Xkcd::sample(1, "Barrel & <Part 1>", "Don't we all."),
+            Xkcd::sample(2, "Petit Trees", "No image stored."),
+            Xkcd::sample(10, "Pi Equals", "Help, I'm trapped."),
+        ];
+        for meta in &comics {
+            storage.insert_meta(meta).unwrap();
+        }
+        for num in [10, 1] {
+            storage
+                .insert_image(num, ImageVariant::Standard, PNG, ImageFormat::Png)
+                .unwrap();
+        }
+
+        let path = std::env::temp_dir().join(format!("xkcd-{}-{:08x}.cbz", process::id(), rand::random::<u32>()));
+        let pages = export(&storage, &comics, &path).unwrap();
+        let mut zip = ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let names = zip.file_names().map(str::to_string).collect::<Vec<_>>();
+        let mut info = String::new();
+        zip.by_name("ComicInfo.xml").unwrap().read_to_string(&mut info).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(pages, 2);
+        let mut in_order = names.clone();
+        in_order.sort();
+        assert_eq!(names, ["01.png", "10.png", "ComicInfo.xml"]);
+        assert_eq!(names, in_order);
+        assert!(info.contains("<PageCount>2</PageCount>"));
+        assert!(info.contains("<Page Image=\"0\" Bookmark=\"1: Barrel &amp; &lt;Part 1&gt;\" />"));
+        assert!(info.contains("<Page Image=\"1\" Bookmark=\"10: Pi Equals\" />"));
+        assert!(info.contains("#1 Barrel &amp; &lt;Part 1&gt;: Don&#39;t we all."));
+        assert!(!info.contains("Petit Trees"));
+    }
+}
@@ -0,0 +1,171 @@
+use std::{fmt::Write as _, fs, path::Path};
+
+use anyhow::Context;
+use log::info;
+
+use crate::{
+    export::{escape_xml as escape, stored_image},
+    storage::Storage,
+    xkcd::Xkcd,
+};
+
+const STYLE: &str = "body { font-family: sans-serif; max-width: 60em; margin: 2em auto; text-align: center; }
+img { max-width: 100%; }
+nav a { margin: 0 1em; }
+.alt { font-style: italic; }
+.transcript { text-align: left; white-space: pre-wrap; }
+ul { list-style: none; padding: 0; text-align: left; }";
+
+struct Page<'a> {
+    meta: &'a Xkcd,
+    image: String,
+}
+
+/// Writes a self-contained gallery of `comics` into `dir`: `index.html`, one `N.html` per comic and the images in
+/// `images/`. Returns the number of comic pages written.
+pub fn export(storage: &impl Storage, comics: &[Xkcd], dir: &Path) -> anyhow::Result<usize> {
+    let images_dir = dir.join("images");
+    fs::create_dir_all(&images_dir).with_context(|| format!("Failed to create {}", images_dir.display()))?;
+
+    let mut pages = vec![];
+    for meta in comics {
+        let Some((image, format)) = stored_image(storage, meta.num) else {
+            continue;
+        };
+        let name = format!("{}.{}", meta.num, format.extension());
+        fs::write(images_dir.join(&name), image)?;
+        pages.push(Page { meta, image: name });
+    }
+
+    for (i, page) in pages.iter().enumerate() {
+        let prev = i.checked_sub(1).and_then(|i| pages.get(i));
+        let next = pages.get(i + 1);
+        let random = &pages[rand::random_range(..pages.len())];
+        let path = dir.join(page_name(page.meta));
+        info!("Writing {}", path.display());
+        fs::write(&path, comic_page(page, prev, next, random))?;
+    }
+    fs::write(dir.join("index.html"), index_page(&pages))?;
+    Ok(pages.len())
+}
+
+fn page_name(meta: &Xkcd) -> String { format!("{}.html", meta.num) }
+
+fn document(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta \
+         charset=\"utf-8\">\n<title>{}</title>\n<style>\n{STYLE}\n</style>\n</head>\n<body>\n{body}</body>\n</html>\n",
+        escape(title)
+    )
+}
+
+fn link(label: &str, page: Option<&Page>) -> String {
+    match page {
+        Some(page) => format!("<a href=\"{}\">{label}</a>", page_name(page.meta)),
+        None => format!("<span>{label}</span>"),
+    }
+}
+
+fn comic_page(page: &Page, prev: Option<&Page>, next: Option<&Page>, random: &Page) -> String {
+    let meta = page.meta;
+    let mut body = String::new();
+    let _ = writeln!(body, "<h1>{}</h1>", escape(&meta.title));
+    let _ = writeln!(
+        body,
+        "<nav>{}<a href=\"index.html\">Index</a>{}{}</nav>",
+        link("&lt; Prev", prev),
+        link("Random", Some(random)),
+        link("Next &gt;", next)
+    );
+    let _ = writeln!(
+        body,
+        "<figure>\n<img src=\"images/{}\" alt=\"{}\" title=\"{}\">\n<figcaption \
+         class=\"alt\">{}</figcaption>\n</figure>",
+        page.image,
+        escape(&meta.title),
+        escape(&meta.alt),
+        escape(&meta.alt)
+    );
+    let _ = writeln!(
+        body,
+        "<p>#{} &middot; {}</p>",
+        meta.num,
+        meta.release_date.format("%Y-%m-%d")
+    );
+    if !meta.transcript.is_empty() {
+        let _ = writeln!(
+            body,
+            "<h2>Transcript</h2>\n<div class=\"transcript\">{}</div>",
+            escape(&meta.transcript)
+        );
+    }
+    document(&format!("xkcd {}: {}", meta.num, meta.title), &body)
+}
+
+fn index_page(pages: &[Page]) -> String {
+    let mut body = String::from("<h1>xkcd</h1>\n<ul>\n");
+    for page in pages {
+        let _ = writeln!(
+            body,
+            "<li>{} <a href=\"{}\">{}</a></li>",
+            page.meta.release_date.format("%Y-%m-%d"),
+            page_name(page.meta),
+            escape(&page.meta.title)
+        );
+    }
+    body.push_str("</ul>\n");
+    document("xkcd", &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+    use crate::{
+        image::{ImageFormat, ImageVariant},
+        storage::BlockingXkcdStorage,
+    };
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\0IEND\xaeB`\x82";
+
+    #[test]
+    fn pages_are_escaped_and_linked_across_gaps() {
+        let storage = BlockingXkcdStorage::memory();
+        let comics = [
+            Xkcd::sample(1, "Barrel & <Part 1>", "\"Don't\" <we> all."),
+            Xkcd::sample(2, "Petit Trees", "No image stored."),
+            Xkcd::sample(3, "Island", "Sketch."),
+            Xkcd::sample(4, "Landscape", "Sketch."),
+        ];
+        for meta in &comics {
+            storage.insert_meta(meta).unwrap();
+        }
+        for num in [1, 3, 4] {
+            storage
+                .insert_image(num, ImageVariant::Standard, PNG, ImageFormat::Png)
+                .unwrap();
+        }
+
+        let dir = std::env::temp_dir().join(format!("xkcd-html-{}-{:08x}", process::id(), rand::random::<u32>()));
+        let pages = export(&storage, &comics, &dir).unwrap();
+        let read = |name: &str| fs::read_to_string(dir.join(name));
+        let (first, last, gap, index) = (read("1.html"), read("4.html"), read("2.html"), read("index.html"));
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(pages, 3);
+        assert!(gap.is_err());
+        let (first, last, index) = (first.unwrap(), last.unwrap(), index.unwrap());
+
+        assert!(first.contains("<h1>Barrel &amp; &lt;Part 1&gt;</h1>"));
+        assert!(first.contains("<title>xkcd 1: Barrel &amp; &lt;Part 1&gt;</title>"));
+        assert!(first.contains("alt=\"Barrel &amp; &lt;Part 1&gt;\" title=\"&quot;Don&#39;t&quot; &lt;we&gt; all.\""));
+        assert!(!first.contains("<Part 1>") && !first.contains("<we>"));
+        assert!(index.contains(">Barrel &amp; &lt;Part 1&gt;</a>"));
+
+        assert!(first.contains("<span>&lt; Prev</span>"));
+        assert!(first.contains("<a href=\"3.html\">Next &gt;</a>"));
+        assert!(last.contains("<a href=\"3.html\">&lt; Prev</a>"));
+        assert!(last.contains("<span>Next &gt;</span>"));
+    }
+}
@@ -0,0 +1,73 @@
+use chrono::Datelike;
+use log::warn;
+
+use crate::{
+    image::{ImageFormat, ImageVariant},
+    storage::Storage,
+    xkcd::Xkcd,
+};
+
+pub mod cbz;
+pub mod html;
+
+/// Which stored comics to export. Unset bounds are open.
+#[derive(Debug)]
+pub struct ExportFilter {
+    pub start: Option<u32>,
+    pub end: Option<u32>,
+    pub year: Option<i32>,
+}
+
+impl ExportFilter {
+    fn contains_num(&self, num: u32) -> bool {
+        self.start.is_none_or(|start| num >= start) && self.end.is_none_or(|end| num <= end)
+    }
+
+    fn contains(&self, meta: &Xkcd) -> bool {
+        self.contains_num(meta.num) && self.year.is_none_or(|year| meta.release_date.year() == year)
+    }
+}
+
+/// Stored comics matching `filter`, in numeric order.
+pub fn select(storage: &impl Storage, filter: &ExportFilter) -> anyhow::Result<Vec<Xkcd>> {
+    let mut ids = storage.get_stored_ids()?;
+    ids.retain(|num| filter.contains_num(*num));
+    ids.sort_unstable();
+    let comics = ids
+        .into_iter()
+        .filter_map(|num| {
+            storage
+                .get_meta(num)
+                .map_err(|e| warn!("Skipping xkcd {num}: {e}"))
+                .ok()
+        })
+        .filter(|meta| filter.contains(meta))
+        .collect();
+    Ok(comics)
+}
+
+/// The stored image of the comic and its format, `None` if it is not stored. Exports only use what is stored,
+/// `get_image` alone would go and fetch missing images.
+pub(crate) fn stored_image(storage: &impl Storage, num: u32) -> Option<(Vec<u8>, ImageFormat)> {
+    let image = storage
+        .get_image_format(num, ImageVariant::Standard)
+        .and_then(|format| Ok((storage.get_image(num, ImageVariant::Standard)?, format)));
+    image
+        .map_err(|e| warn!("Skipping xkcd {num} without stored image: {e}"))
+        .ok()
+}
+
+pub(crate) fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -1,19 +1,35 @@
-#[derive(Debug)]
+use crate::image::ImageFormat;
+
+#[derive(Debug, Clone, Copy)]
 #[repr(u64)]
 pub enum XkcdFile {
     // Root,
     // Dir(u32),
     Num(u32),
     Title(u32),
-    Image(u32),
+    Image(u32, ImageFormat),
     Alt(u32),
     Transcript(u32),
     ReleaseDate(u32),
+    Json(u32),
+    Image2x(u32, ImageFormat),
 }
 
 pub enum XkcdDir {
     Root,
     Dir(u32),
+    ByDate,
+    Year(i32),
+    Month(i32, u32),
+    Search,
+    Query(u32, String),
+}
+
+/// Symlinks at the root that point at a comic directory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum XkcdLink {
+    Latest,
+    Random,
 }
 
 impl XkcdDir {
@@ -21,37 +37,102 @@ impl XkcdDir {
         match self {
             XkcdDir::Root => 1,
             XkcdDir::Dir(num) => ((*num as u64) << 32) | 2,
+            XkcdDir::ByDate => 9,
+            XkcdDir::Year(year) => ((*year as u64) << 32) | 10,
+            XkcdDir::Month(year, month) => ((((*year as u64) << 4) | *month as u64) << 32) | 11,
+            XkcdDir::Search => 12,
+            XkcdDir::Query(id, _) => ((*id as u64) << 32) | 13,
         }
     }
 
+    /// The directory behind an inode number, except for search queries whose text is not part of the number.
+    pub fn from_inode(ino: u64) -> Option<Self> {
+        let (high, tag) = (ino >> 32, ino & 0xFFFF_FFFF);
+        match (high, tag) {
+            (0, 1) => Some(XkcdDir::Root),
+            (num, 2) => Some(XkcdDir::Dir(num as u32)),
+            (0, 9) => Some(XkcdDir::ByDate),
+            (year, 10) => Some(XkcdDir::Year(year as i32)),
+            (year_month, 11) => Some(XkcdDir::Month((year_month >> 4) as i32, (year_month & 0xF) as u32)),
+            (0, 12) => Some(XkcdDir::Search),
+            _ => None,
+        }
+    }
+
+    /// Number of the comic a well-formed `xkcd_N` directory name stands for.
+    pub fn num_from_name(name: &str) -> Option<u32> {
+        let digits = name.strip_prefix("xkcd_")?;
+        if digits.is_empty() || digits.starts_with('0') || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    }
+
     pub fn name(&self) -> String {
         match self {
             XkcdDir::Root => ".".to_string(),
             XkcdDir::Dir(num) => format!("xkcd_{}", num),
+            XkcdDir::ByDate => "by-date".to_string(),
+            XkcdDir::Year(year) => year.to_string(),
+            XkcdDir::Month(_, month) => format!("{:02}", month),
+            XkcdDir::Search => "search".to_string(),
+            XkcdDir::Query(_, query) => query.clone(),
+        }
+    }
+}
+
+impl XkcdLink {
+    pub const fn inode(&self) -> u64 {
+        match self {
+            XkcdLink::Latest => 16,
+            XkcdLink::Random => 17,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            XkcdLink::Latest => "latest".to_string(),
+            XkcdLink::Random => "random".to_string(),
         }
     }
 }
 
 impl XkcdFile {
+    const TAGS: [u64; 8] = [3, 4, 5, 6, 7, 8, 14, 15];
+
     pub fn name(&self) -> String {
         match self {
-            XkcdFile::Image(n) => format!("xkcd_{}.png", n),
+            XkcdFile::Image(n, format) => format!("xkcd_{}.{}", n, format.extension()),
             XkcdFile::Alt(n) => format!("xkcd_{}.alt", n),
             XkcdFile::Num(n) => format!("xkcd_{}.num", n),
             XkcdFile::Title(n) => format!("xkcd_{}.title", n),
             XkcdFile::Transcript(n) => format!("xkcd_{}.transcript", n),
             XkcdFile::ReleaseDate(n) => format!("xkcd_{}.release_date", n),
+            XkcdFile::Json(_) => "info.json".to_string(),
+            XkcdFile::Image2x(n, format) => format!("xkcd_{}_2x.{}", n, format.extension()),
         }
     }
 
+    /// Number of the comic a file inode number belongs to.
+    pub fn num_from_inode(ino: u64) -> Option<u32> {
+        Self::TAGS.contains(&(ino & 0xFFFF_FFFF)).then_some((ino >> 32) as u32)
+    }
+
+    /// Inode numbers of every file a comic directory can contain.
+    pub fn inodes(num: u32) -> impl Iterator<Item = u64> {
+        Self::TAGS.into_iter().map(move |tag| ((num as u64) << 32) | tag)
+    }
+
     pub(crate) fn inode(&self) -> u64 {
         match self {
             XkcdFile::Num(num) => ((*num as u64) << 32) | 3,
             XkcdFile::Title(num) => ((*num as u64) << 32) | 4,
-            XkcdFile::Image(num) => ((*num as u64) << 32) | 5,
+            XkcdFile::Image(num, _) => ((*num as u64) << 32) | 5,
             XkcdFile::Alt(num) => ((*num as u64) << 32) | 6,
             XkcdFile::Transcript(num) => ((*num as u64) << 32) | 7,
             XkcdFile::ReleaseDate(num) => ((*num as u64) << 32) | 8,
+            XkcdFile::Json(num) => ((*num as u64) << 32) | 14,
+            XkcdFile::Image2x(num, _) => ((*num as u64) << 32) | 15,
         }
     }
 }
@@ -0,0 +1,66 @@
+use std::{sync::mpsc::Sender, thread, time::Duration};
+
+use chrono::{Datelike, Utc};
+use fuser::Notifier;
+use log::info;
+
+use crate::{
+    fs::file::{XkcdDir, XkcdFile, XkcdLink},
+    storage::{BlockingXkcdStorage, Storage},
+};
+
+/// Periodically fetches new comics while the filesystem is mounted, and picks up comics changed in the store since
+/// the last poll, e.g. by a `refresh` or `verify --repair` in another process.
+///
+/// Numbers of the new and changed comics are sent to the [`XkcdFS`](super::xkcd_fs::XkcdFS), which adds or rebuilds
+/// them on the next request, and the directories they appear in are invalidated in the kernel's cache.
+pub struct LiveUpdate {
+    pub storage: BlockingXkcdStorage,
+    pub interval: Duration,
+}
+
+impl LiveUpdate {
+    pub fn spawn(self, updates: Sender<u32>, notifier: Notifier) {
+        thread::spawn(move || {
+            let mut since = Utc::now().timestamp();
+            loop {
+                thread::sleep(self.interval);
+                info!("Polling for new xkcds");
+                let now = Utc::now().timestamp();
+                let mut nums = self.storage.fetch_new();
+                nums.extend(self.storage.changed_since(since).unwrap_or_default());
+                nums.sort_unstable();
+                nums.dedup();
+                since = now;
+                for num in nums {
+                    if updates.send(num).is_err() {
+                        return;
+                    }
+                    self.invalidate(&notifier, num);
+                }
+            }
+        });
+    }
+
+    fn invalidate(&self, notifier: &Notifier, num: u32) {
+        let mut dirs = vec![XkcdDir::Root, XkcdDir::ByDate, XkcdDir::Dir(num)];
+        if let Ok(meta) = self.storage.get_meta(num) {
+            let (year, month) = (meta.release_date.year(), meta.release_date.month());
+            dirs.extend([XkcdDir::Year(year), XkcdDir::Month(year, month)]);
+        }
+        for dir in dirs {
+            // Fails for directories the kernel has not seen yet, which is fine.
+            if let Err(e) = notifier.inval_inode(dir.inode(), 0, 0) {
+                info!("Failed to invalidate {}: {e}", dir.name());
+            }
+        }
+        // `latest` may point at the new comic now.
+        if let Err(e) = notifier.inval_inode(XkcdLink::Latest.inode(), 0, 0) {
+            info!("Failed to invalidate {}: {e}", XkcdLink::Latest.name());
+        }
+        // Contents and sizes of the files of a changed comic.
+        for ino in XkcdFile::inodes(num) {
+            let _ = notifier.inval_inode(ino, 0, 0);
+        }
+    }
+}
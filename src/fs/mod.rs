@@ -1,18 +1,36 @@
-use std::path::Path;
+use std::{path::Path, sync::mpsc};
 
-use fuser::{MountOption, mount2};
+use fuser::{MountOption, Session};
+use log::error;
 
-use crate::storage::Storage;
+use crate::{fs::live_update::LiveUpdate, storage::Storage};
 
 pub mod file;
+pub mod live_update;
 pub mod xkcd_fs;
 
-pub fn fuse<St: Storage>(mount_point: &Path, storage: St) {
-    let xkcd_fuse = xkcd_fs::XkcdFS::new(storage);
+pub fn fuse<St: Storage>(mount_point: &Path, storage: St, live_update: Option<LiveUpdate>, fetch_on_access: bool) {
+    let mut xkcd_fuse = xkcd_fs::XkcdFS::new(storage);
+    if fetch_on_access {
+        xkcd_fuse = xkcd_fuse.with_fetch_on_access();
+    }
+    let (updates_tx, updates_rx) = mpsc::channel();
+    if live_update.is_some() {
+        xkcd_fuse = xkcd_fuse.with_updates(updates_rx);
+    }
     let options = vec![MountOption::AutoUnmount, MountOption::AllowRoot];
     println!("Mounting xkcd at {}", mount_point.display());
-    let _mount = mount2(xkcd_fuse, mount_point, &options);
-
-    // let _mount = spawn_mount2(xkcd_fuse, &mount_point, &options);
-    // let _ = io::stdin().read_line(&mut String::new()).unwrap();
+    let mut session = match Session::new(xkcd_fuse, mount_point, &options) {
+        Ok(session) => session,
+        Err(e) => {
+            error!("Failed to mount xkcd at {}: {e}", mount_point.display());
+            return;
+        }
+    };
+    if let Some(live_update) = live_update {
+        live_update.spawn(updates_tx, session.notifier());
+    }
+    if let Err(e) = session.run() {
+        error!("FUSE session failed: {e}");
+    }
 }
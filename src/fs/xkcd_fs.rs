@@ -1,17 +1,22 @@
 use std::{
-    cmp::min,
-    collections::HashMap,
+    collections::{HashMap, hash_map::Entry},
     ffi::{OsStr, OsString},
+    sync::mpsc::Receiver,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use fuser::{FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request};
-use libc::{EISDIR, ENOENT, c_int};
+use chrono::Datelike;
+use fuser::{
+    FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyXattr, Request,
+};
+use indexmap::IndexMap;
+use libc::{EAGAIN, EINVAL, EIO, EISDIR, ENODATA, ENOENT, ERANGE, c_int};
 use log::{info, warn};
 
 use crate::{
-    fs::file::{XkcdDir, XkcdFile},
-    storage::Storage,
+    fs::file::{XkcdDir, XkcdFile, XkcdLink},
+    image::{ImageFormat, ImageVariant},
+    storage::{Storage, StorageError, slice},
     xkcd::Xkcd,
 };
 
@@ -20,6 +25,16 @@ pub struct XkcdFS<S: Storage> {
     inodes: HashMap<u64, INode>,
     ttl: Duration,
     storage: S,
+    updates: Option<Receiver<u32>>,
+    /// The `by-date` tree needs the release date of every comic, so it is only built once it is visited.
+    by_date_loaded: bool,
+    /// Newest comic known to exist, the upper bound for fetching on access.
+    latest: u32,
+    fetch_on_access: bool,
+    /// Target of `random`, picked anew on every lookup.
+    random: Option<u32>,
+    /// Id of the most recent directory under `search/`.
+    last_query_id: u32,
 }
 
 #[derive(Debug)]
@@ -51,36 +66,309 @@ impl INode {
                 }
             },
             kind: INodeKind::Directory(Directory {
-                children: HashMap::new(),
+                children: IndexMap::new(),
                 parent,
             }),
         }
     }
+
+    fn link(link: XkcdLink) -> Self {
+        INode {
+            attrs: FileAttr {
+                ino: link.inode(),
+                size: 0,
+                blocks: 0,
+                atime: UNIX_EPOCH,
+                mtime: UNIX_EPOCH,
+                ctime: UNIX_EPOCH,
+                crtime: UNIX_EPOCH,
+                kind: FileType::Symlink,
+                perm: 0o777,
+                nlink: 1,
+                uid: 1000,
+                gid: 10,
+                rdev: 0,
+                flags: 0,
+                blksize: 512,
+            },
+            kind: INodeKind::Link(link),
+        }
+    }
 }
 
 #[derive(Debug)]
 enum INodeKind {
     Directory(Directory),
     File(XkcdFile),
+    Link(XkcdLink),
 }
 
 #[derive(Debug)]
 struct Directory {
-    children: HashMap<OsString, u64>,
+    children: IndexMap<OsString, u64>,
     parent: Option<u64>,
 }
 
 impl<St: Storage> XkcdFS<St> {
+    const BY_DATE_INO: u64 = XkcdDir::ByDate.inode();
+    /// Queries kept under `search/`, the least recently looked up ones are dropped first.
+    const MAX_QUERIES: usize = 64;
     const ROOT_INO: u64 = XkcdDir::Root.inode();
+    const SEARCH_INO: u64 = XkcdDir::Search.inode();
+    const XATTRS: [&str; 5] = [
+        "user.xkcd.title",
+        "user.xkcd.alt",
+        "user.xkcd.num",
+        "user.xkcd.release_date",
+        "user.xkcd.link",
+    ];
 
     pub fn new(xkcd_storage: St) -> Self {
         let mut inodes = HashMap::new();
         inodes.insert(Self::ROOT_INO, INode::dir(Self::ROOT_INO, None, UNIX_EPOCH));
+        Self::ensure_dir(&mut inodes, XkcdDir::ByDate, Self::ROOT_INO);
+        Self::ensure_dir(&mut inodes, XkcdDir::Search, Self::ROOT_INO);
+        for link in [XkcdLink::Latest, XkcdLink::Random] {
+            inodes.insert(link.inode(), INode::link(link));
+            Self::add_child(&mut inodes, Self::ROOT_INO, link.name().into(), link.inode());
+        }
 
         Self {
             inodes,
             ttl: Duration::from_secs(60),
             storage: xkcd_storage,
+            updates: None,
+            by_date_loaded: false,
+            latest: 0,
+            fetch_on_access: false,
+            random: None,
+            last_query_id: 0,
+        }
+    }
+
+    /// Comics received through `updates` are added to the tree on the following requests, or rebuilt if they are in
+    /// it already.
+    pub fn with_updates(mut self, updates: Receiver<u32>) -> Self {
+        self.updates = Some(updates);
+        self
+    }
+
+    /// Looking up a comic that is not stored fetches it, as long as it is not newer than the newest stored one.
+    pub fn with_fetch_on_access(mut self) -> Self {
+        self.fetch_on_access = true;
+        self
+    }
+
+    fn apply_updates(&mut self) {
+        let Some(updates) = &self.updates else { return };
+        for num in updates.try_iter().collect::<Vec<_>>() {
+            self.storage.evict(num);
+            if self.is_stored(num) {
+                info!("Rebuilding changed xkcd {num}");
+                self.forget_dir(num);
+                if self.by_date_loaded
+                    && let Ok(meta) = self.storage.get_meta(num)
+                {
+                    Self::unlink_by_date(&mut self.inodes, &meta);
+                }
+            } else {
+                info!("Adding new xkcd {num}");
+            }
+            self.add_xkcd(num);
+        }
+    }
+
+    /// Drops the built inodes of the comic directory, so it is built again with the current sizes when it is next
+    /// accessed.
+    fn forget_dir(&mut self, num: u32) {
+        if let Some(INode {
+            kind: INodeKind::Directory(dir),
+            ..
+        }) = self.inodes.remove(&XkcdDir::Dir(num).inode())
+        {
+            for ino in dir.children.values() {
+                self.inodes.remove(ino);
+            }
+        }
+    }
+
+    /// Adds a stored comic to the root, and to the `by-date` tree if that is already built.
+    fn add_xkcd(&mut self, num: u32) {
+        let dir = XkcdDir::Dir(num);
+        Self::add_child(&mut self.inodes, Self::ROOT_INO, dir.name().into(), dir.inode());
+        self.latest = self.latest.max(num);
+        if self.by_date_loaded
+            && let Ok(meta) = self.storage.get_meta(num)
+        {
+            Self::link_by_date(&mut self.inodes, &meta);
+        }
+    }
+
+    /// Fetches the comic `name` stands for if fetching on access is enabled and it is not stored yet.
+    fn fetch_on_access(&mut self, name: &OsStr) -> Result<(), StorageError> {
+        let Some(num) = name.to_str().and_then(XkcdDir::num_from_name) else {
+            return Ok(());
+        };
+        if !self.fetch_on_access || self.is_stored(num) || num > self.latest {
+            return Ok(());
+        }
+        info!("Fetching xkcd {num} on access");
+        // Images too, their sizes are needed when the directory is built.
+        self.storage.get_xkcd(num)?;
+        self.add_xkcd(num);
+        Ok(())
+    }
+
+    fn is_stored(&self, num: u32) -> bool {
+        match self.inodes.get(&Self::ROOT_INO).map(|inode| &inode.kind) {
+            Some(INodeKind::Directory(root)) => root.children.contains_key(OsStr::new(&XkcdDir::Dir(num).name())),
+            _ => false,
+        }
+    }
+
+    /// Builds the inodes behind `ino` if they do not exist yet: the whole comic directory for a comic directory or
+    /// one of its files, the whole `by-date` tree for anything in it.
+    fn ensure_inode(&mut self, ino: u64) -> Result<(), StorageError> {
+        if let Some(XkcdDir::ByDate | XkcdDir::Year(_) | XkcdDir::Month(..)) = XkcdDir::from_inode(ino) {
+            self.load_by_date();
+        }
+        if self.inodes.contains_key(&ino) {
+            return Ok(());
+        }
+        let num = match XkcdDir::from_inode(ino) {
+            Some(XkcdDir::Dir(num)) => num,
+            _ => XkcdFile::num_from_inode(ino).ok_or(StorageError::NotFound)?,
+        };
+        if !self.is_stored(num) {
+            return Err(StorageError::NotFound);
+        }
+        info!("Building directory of xkcd {num}");
+        let meta = self.storage.get_meta(num)?;
+        Self::init_dir(&self.storage, &mut self.inodes, &meta);
+        Ok(())
+    }
+
+    /// Numbers of the comic directories at the root.
+    fn stored_nums(&self) -> Vec<u32> {
+        match self.inodes.get(&Self::ROOT_INO).map(|inode| &inode.kind) {
+            Some(INodeKind::Directory(root)) => root
+                .children
+                .values()
+                .filter_map(|ino| match XkcdDir::from_inode(*ino) {
+                    Some(XkcdDir::Dir(num)) => Some(num),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Resolves the symlink `ino` and updates its size to the length of the target, `reroll` picks a new comic for
+    /// `random`.
+    fn resolve_link(&mut self, ino: u64, reroll: bool) -> Option<(FileAttr, String)> {
+        let INodeKind::Link(link) = self.inodes.get(&ino)?.kind else {
+            return None;
+        };
+        let num = match link {
+            XkcdLink::Latest => Some(self.latest).filter(|num| *num > 0),
+            XkcdLink::Random => {
+                if reroll || self.random.is_none() {
+                    let nums = self.stored_nums();
+                    self.random = (!nums.is_empty()).then(|| nums[rand::random_range(..nums.len())]);
+                }
+                self.random
+            }
+        }?;
+        let target = XkcdDir::Dir(num).name();
+        let inode = self.inodes.get_mut(&ino)?;
+        inode.attrs.size = target.len() as u64;
+        Some((inode.attrs, target))
+    }
+
+    /// `random` must not be cached by the kernel, or it would keep pointing at the same comic.
+    fn link_ttl(&self, ino: u64) -> Duration {
+        if ino == XkcdLink::Random.inode() {
+            Duration::ZERO
+        } else {
+            self.ttl
+        }
+    }
+
+    fn load_by_date(&mut self) {
+        if self.by_date_loaded {
+            return;
+        }
+        self.by_date_loaded = true;
+        let nums = self.stored_nums();
+        info!("Building by-date tree of {} xkcds", nums.len());
+        for num in nums {
+            if let Ok(meta) = self.storage.get_meta(num) {
+                Self::link_by_date(&mut self.inodes, &meta);
+            }
+        }
+    }
+
+    /// Kind of a child, which may not have been built yet.
+    fn file_type(&self, ino: u64) -> FileType {
+        match self.inodes.get(&ino).map(|inode| &inode.kind) {
+            Some(INodeKind::File(_)) => FileType::RegularFile,
+            Some(INodeKind::Directory(_)) => FileType::Directory,
+            Some(INodeKind::Link(_)) => FileType::Symlink,
+            None if XkcdDir::from_inode(ino).is_some() => FileType::Directory,
+            None => FileType::RegularFile,
+        }
+    }
+
+    /// The comic whose metadata `ino` carries as extended attributes, only comic directories and images have any.
+    fn xattr_num(&mut self, ino: u64) -> Result<Option<u32>, StorageError> {
+        self.ensure_inode(ino)?;
+        Ok(match self.inodes.get(&ino).map(|inode| &inode.kind) {
+            Some(INodeKind::Directory(_)) => match XkcdDir::from_inode(ino) {
+                Some(XkcdDir::Dir(num)) => Some(num),
+                _ => None,
+            },
+            Some(INodeKind::File(XkcdFile::Image(num, _) | XkcdFile::Image2x(num, _))) => Some(*num),
+            _ => None,
+        })
+    }
+
+    fn xattr(meta: &Xkcd, name: &OsStr) -> Option<String> {
+        match name.to_str()? {
+            "user.xkcd.title" => Some(meta.title.clone()),
+            "user.xkcd.alt" => Some(meta.alt.clone()),
+            "user.xkcd.num" => Some(meta.num.to_string()),
+            "user.xkcd.release_date" => Some(meta.release_date.to_string()),
+            "user.xkcd.link" => Some(meta.link.clone()),
+            _ => None,
+        }
+    }
+
+    /// A `size` of 0 asks for the size of the value only.
+    fn reply_xattr(data: &[u8], size: u32, reply: ReplyXattr) {
+        if size == 0 {
+            reply.size(data.len() as u32);
+        } else if data.len() > size as usize {
+            reply.error(ERANGE);
+        } else {
+            reply.data(data);
+        }
+    }
+
+    fn errno(e: &StorageError) -> c_int {
+        match e {
+            StorageError::NotFound => ENOENT,
+            StorageError::Unavailable(_) => EAGAIN,
+            StorageError::Io(_) => EIO,
+            StorageError::Corrupted(_) => ENODATA,
+        }
+    }
+
+    /// Like [`errno`](Self::errno), but `getxattr` callers take `ENODATA` for a missing attribute, so corrupted data
+    /// is an I/O error there.
+    fn xattr_errno(e: &StorageError) -> c_int {
+        match e {
+            StorageError::Corrupted(_) => EIO,
+            e => Self::errno(e),
         }
     }
 
@@ -111,10 +399,12 @@ impl<St: Storage> XkcdFS<St> {
         let size = match file {
             XkcdFile::Num(n) => n.to_string().len() as u64,
             XkcdFile::Title(_) => meta.title.len() as u64,
-            XkcdFile::Image(_) => storage.get_image_size(meta.num).unwrap_or(0),
+            XkcdFile::Image(..) => storage.get_image_size(meta.num, ImageVariant::Standard).unwrap_or(0),
+            XkcdFile::Image2x(..) => storage.get_image_size(meta.num, ImageVariant::Double).unwrap_or(0),
             XkcdFile::Alt(_) => meta.alt.len() as u64,
             XkcdFile::Transcript(_) => meta.transcript.len() as u64,
             XkcdFile::ReleaseDate(_) => meta.release_date.to_string().len() as u64,
+            XkcdFile::Json(_) => meta.to_json().len() as u64,
         };
 
         let file_attr = XkcdFS::<St>::init_file_attr(file_ino, sys_time, size);
@@ -124,30 +414,147 @@ impl<St: Storage> XkcdFS<St> {
 
     fn init_meta_files(storage: &St, meta: &Xkcd) -> Vec<(XkcdFile, FileAttr, OsString)> {
         let mut meta_files = vec![];
+        let format = storage
+            .get_image_format(meta.num, ImageVariant::Standard)
+            .ok()
+            .or_else(|| ImageFormat::from_url(&meta.image_url))
+            .unwrap_or(ImageFormat::Png);
+        let format_2x = storage.get_image_format(meta.num, ImageVariant::Double).ok();
         let files = [
-            XkcdFile::Image(meta.num),
+            XkcdFile::Image(meta.num, format),
             XkcdFile::Num(meta.num),
             XkcdFile::Title(meta.num),
             XkcdFile::Alt(meta.num),
             XkcdFile::Transcript(meta.num),
             XkcdFile::ReleaseDate(meta.num),
+            XkcdFile::Json(meta.num),
         ];
-        for file in files {
+        let files_2x = format_2x.map(|format| XkcdFile::Image2x(meta.num, format));
+        for file in files.into_iter().chain(files_2x) {
             let (file_attr, name) = Self::init_meta_file(storage, meta, &file);
             meta_files.push((file, file_attr, name));
         }
         meta_files
     }
 
-    fn init_dir(storage: &St, inodes: &mut HashMap<u64, INode>, meta: &Xkcd) -> (OsString, u64) {
-        let dir = XkcdDir::Dir(meta.num);
-        let ino = dir.inode();
+    fn init_dir(storage: &St, inodes: &mut HashMap<u64, INode>, meta: &Xkcd) {
+        let ino = XkcdDir::Dir(meta.num).inode();
         let mut dir_inode = INode::dir(ino, Some(Self::ROOT_INO), meta.release_date_as_timestamp());
 
         Self::init_dir_contents(storage, inodes, meta, &mut dir_inode);
 
         inodes.insert(ino, dir_inode);
-        (dir.name().into(), ino)
+    }
+
+    /// Links the comic directory into the `by-date/YYYY/MM/` tree.
+    fn link_by_date(inodes: &mut HashMap<u64, INode>, meta: &Xkcd) {
+        let dir = XkcdDir::Dir(meta.num);
+        let (name, ino) = (OsString::from(dir.name()), dir.inode());
+        let (year, month) = (meta.release_date.year(), meta.release_date.month());
+        let year_ino = Self::ensure_dir(inodes, XkcdDir::Year(year), Self::BY_DATE_INO);
+        let month_ino = Self::ensure_dir(inodes, XkcdDir::Month(year, month), year_ino);
+        Self::add_child(inodes, month_ino, name, ino);
+    }
+
+    /// Removes the comic from every month of the `by-date` tree but the one of its release date, along with months
+    /// and years that end up empty. The release date it was linked under is not known any more.
+    fn unlink_by_date(inodes: &mut HashMap<u64, INode>, meta: &Xkcd) {
+        let name = OsString::from(XkcdDir::Dir(meta.num).name());
+        let current = XkcdDir::Month(meta.release_date.year(), meta.release_date.month()).inode();
+        let mut emptied = vec![];
+        for (ino, inode) in inodes.iter_mut() {
+            if *ino != current
+                && let Some(XkcdDir::Month(..)) = XkcdDir::from_inode(*ino)
+                && let INodeKind::Directory(dir) = &mut inode.kind
+                && dir.children.shift_remove(&name).is_some()
+                && dir.children.is_empty()
+            {
+                emptied.push(*ino);
+            }
+        }
+        while let Some(ino) = emptied.pop() {
+            let Some(INode {
+                kind: INodeKind::Directory(Directory {
+                    parent: Some(parent), ..
+                }),
+                ..
+            }) = inodes.remove(&ino)
+            else {
+                continue;
+            };
+            if let Some(INodeKind::Directory(dir)) = inodes.get_mut(&parent).map(|inode| &mut inode.kind) {
+                dir.children.retain(|_, child| *child != ino);
+                if parent != Self::BY_DATE_INO && dir.children.is_empty() {
+                    emptied.push(parent);
+                }
+            }
+        }
+    }
+
+    /// Creates an empty directory under `parent` unless it already exists.
+    fn ensure_dir(inodes: &mut HashMap<u64, INode>, dir: XkcdDir, parent: u64) -> u64 {
+        let ino = dir.inode();
+        if let Entry::Vacant(entry) = inodes.entry(ino) {
+            entry.insert(INode::dir(ino, Some(parent), UNIX_EPOCH));
+            Self::add_child(inodes, parent, dir.name().into(), ino);
+        }
+        ino
+    }
+
+    /// (Re)runs the search for `query` and fills `search/<query>` with the matching comic directories in the order
+    /// of relevance. Only the [`MAX_QUERIES`](Self::MAX_QUERIES) most recent queries are kept.
+    fn update_search(&mut self, query: &str) {
+        let Some(INodeKind::Directory(search_dir)) =
+            self.inodes.get_mut(&Self::SEARCH_INO).map(|inode| &mut inode.kind)
+        else {
+            return;
+        };
+        // Taken out and put back at the end, so the order of the children is the order of use.
+        let existing = search_dir.children.shift_remove(OsStr::new(query));
+        let mut dropped = existing.into_iter().collect::<Vec<_>>();
+        while search_dir.children.len() >= Self::MAX_QUERIES
+            && let Some((_, ino)) = search_dir.children.shift_remove_index(0)
+        {
+            dropped.push(ino);
+        }
+        for ino in dropped {
+            self.inodes.remove(&ino);
+        }
+        let id = match existing {
+            Some(ino) => (ino >> 32) as u32,
+            None => {
+                self.last_query_id = self.last_query_id.wrapping_add(1);
+                self.last_query_id
+            }
+        };
+        let query_ino = Self::ensure_dir(
+            &mut self.inodes,
+            XkcdDir::Query(id, query.to_string()),
+            Self::SEARCH_INO,
+        );
+
+        let results = self
+            .storage
+            .search(query)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|num| self.is_stored(*num))
+            .map(XkcdDir::Dir)
+            .map(|dir| (dir.name().into(), dir.inode()))
+            .collect();
+        if let Some(query_inode) = self.inodes.get_mut(&query_ino)
+            && let INodeKind::Directory(query_dir) = &mut query_inode.kind
+        {
+            query_dir.children = results;
+        }
+    }
+
+    fn add_child(inodes: &mut HashMap<u64, INode>, parent: u64, name: OsString, ino: u64) {
+        if let Some(parent_inode) = inodes.get_mut(&parent)
+            && let INodeKind::Directory(dir) = &mut parent_inode.kind
+        {
+            dir.children.insert(name, ino);
+        }
     }
 
     fn init_dir_contents(storage: &St, inodes: &mut HashMap<u64, INode>, meta: &Xkcd, meta_inode: &mut INode) {
@@ -167,66 +574,185 @@ impl<St: Storage> XkcdFS<St> {
     }
 }
 
-impl<S: Storage> Filesystem for XkcdFS<S> {
-    fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
-        let mut new_root_children = vec![];
-        let _ = &self
-            .storage
-            .get_stored_ids()
-            .into_iter()
-            .filter_map(|id| self.storage.get_meta(id))
-            .for_each(|meta| new_root_children.push(Self::init_dir(&self.storage, &mut self.inodes, &meta)));
+impl<S: Storage> XkcdFS<S> {
+    /// Adds the names of the stored comics to the root, the directories are built when they are first looked up.
+    fn load_root(&mut self) {
+        for num in self.storage.get_stored_ids().unwrap_or_default() {
+            self.add_xkcd(num);
+        }
+    }
 
-        if let Some(root) = self.inodes.get_mut(&Self::ROOT_INO)
-            && let INodeKind::Directory(root_dir) = &mut root.kind
+    fn lookup_entry(&mut self, parent: u64, name: &OsStr) -> Result<(Duration, FileAttr), c_int> {
+        self.apply_updates();
+        if parent == Self::SEARCH_INO
+            && let Some(query) = name.to_str()
         {
-            for (name, ino) in new_root_children {
-                root_dir.children.insert(name, ino);
-            }
-            Ok(())
-        } else {
-            Err(255)
+            self.update_search(query);
+        }
+
+        if parent == Self::ROOT_INO {
+            self.fetch_on_access(name).map_err(|e| Self::errno(&e))?;
+        }
+        self.ensure_inode(parent).map_err(|e| Self::errno(&e))?;
+        let child_ino = match self.inodes.get(&parent).map(|inode| &inode.kind) {
+            Some(INodeKind::Directory(dir)) => dir.children.get(name).copied(),
+            _ => None,
+        };
+        let child_ino = child_ino.ok_or(ENOENT)?;
+        self.ensure_inode(child_ino).map_err(|e| Self::errno(&e))?;
+        if let Some(INodeKind::Link(_)) = self.inodes.get(&child_ino).map(|inode| &inode.kind) {
+            let (attr, _) = self.resolve_link(child_ino, true).ok_or(ENOENT)?;
+            return Ok((self.link_ttl(child_ino), attr));
+        }
+        match self.inodes.get(&child_ino) {
+            Some(child_inode) => Ok((self.ttl, child_inode.attrs)),
+            None => Err(ENOENT),
         }
     }
 
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        info!("lookup: parent = {}, name = {:?}", parent, name);
-        let Some(parent_inode) = self.inodes.get(&parent) else {
-            reply.error(ENOENT);
-            return;
+    fn attr(&mut self, ino: u64) -> Result<(Duration, FileAttr), c_int> {
+        self.apply_updates();
+        self.ensure_inode(ino).map_err(|e| Self::errno(&e))?;
+        let attr = match self.inodes.get(&ino).map(|inode| &inode.kind) {
+            Some(INodeKind::Link(_)) => self.resolve_link(ino, false).map(|(attr, _)| attr),
+            _ => self.inodes.get(&ino).map(|inode| inode.attrs),
         };
+        Ok((self.link_ttl(ino), attr.ok_or(ENOENT)?))
+    }
 
-        match &parent_inode.kind {
-            INodeKind::Directory(dir) => {
-                if let Some(child_ino) = dir.children.get(name)
-                    && let Some(child_inode) = self.inodes.get(child_ino)
-                {
-                    reply.entry(&self.ttl, &child_inode.attrs, 0);
-                } else {
-                    reply.error(ENOENT);
+    fn link_target(&mut self, ino: u64) -> Result<String, c_int> {
+        self.apply_updates();
+        match self.inodes.get(&ino).map(|inode| &inode.kind) {
+            Some(INodeKind::Link(_)) => self.resolve_link(ino, false).map(|(_, target)| target).ok_or(ENOENT),
+            Some(_) => Err(EINVAL),
+            None => Err(ENOENT),
+        }
+    }
+
+    fn read_data(&mut self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>, c_int> {
+        // Changed comics have to be evicted from the cache before they are read.
+        self.apply_updates();
+        self.ensure_inode(ino).map_err(|e| Self::errno(&e))?;
+
+        let Some(inode) = self.inodes.get(&ino) else {
+            warn!("ino not found");
+            return Err(ENOENT);
+        };
+
+        let file = match &inode.kind {
+            INodeKind::Directory(_) => return Err(EISDIR),
+            INodeKind::Link(_) => return Err(EINVAL),
+            INodeKind::File(file) => *file,
+        };
+        // Not limited to the size taken when the directory was built, the stored comic may have changed since.
+        let len = size as usize;
+        let data = match file {
+            XkcdFile::Image(num, _) | XkcdFile::Image2x(num, _) => {
+                let variant = match file {
+                    XkcdFile::Image2x(..) => ImageVariant::Double,
+                    _ => ImageVariant::Standard,
                 };
+                return self
+                    .storage
+                    .read_image(num, variant, offset, len)
+                    .map_err(|e| Self::errno(&e));
             }
-            INodeKind::File(_) => {
-                if let Some(dir_inode) = self.inodes.get(&parent)
-                    && let INodeKind::Directory(dir) = &dir_inode.kind
-                    && let Some(child_ino) = dir.children.get(name)
-                    && let Some(child_inode) = self.inodes.get(child_ino)
-                {
-                    reply.entry(&self.ttl, &child_inode.attrs, 0);
-                } else {
-                    reply.error(ENOENT);
+            XkcdFile::Num(num)
+            | XkcdFile::Title(num)
+            | XkcdFile::Alt(num)
+            | XkcdFile::Transcript(num)
+            | XkcdFile::ReleaseDate(num)
+            | XkcdFile::Json(num) => {
+                let meta = self.storage.get_meta(num).map_err(|e| Self::errno(&e))?;
+                match file {
+                    XkcdFile::Num(_) => meta.num.to_string(),
+                    XkcdFile::Title(_) => meta.title,
+                    XkcdFile::Alt(_) => meta.alt,
+                    XkcdFile::Transcript(_) => meta.transcript,
+                    XkcdFile::ReleaseDate(_) => meta.release_date.to_string(),
+                    XkcdFile::Json(_) => meta.to_json(),
+                    _ => unreachable!(),
                 }
             }
         };
+        // Keeps `getattr` in line with what is read.
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.attrs.size = data.len() as u64;
+        }
+        Ok(slice(data.as_bytes(), offset, len).to_vec())
+    }
+
+    /// Entries of the directory including `.` and `..`, in the order `readdir` lists them.
+    fn dir_entries(&mut self, ino: u64) -> Result<Vec<(u64, FileType, OsString)>, c_int> {
+        self.apply_updates();
+        self.ensure_inode(ino).map_err(|e| Self::errno(&e))?;
+
+        let Some(inode) = self.inodes.get(&ino) else {
+            return Err(ENOENT);
+        };
+        let INodeKind::Directory(dir) = &inode.kind else {
+            return Err(ENOENT);
+        };
+        let mut entries = vec![(ino, FileType::Directory, OsString::from("."))];
+        entries.extend(
+            dir.parent
+                .map(|parent| (parent, FileType::Directory, OsString::from(".."))),
+        );
+        entries.extend(
+            dir.children
+                .iter()
+                .map(|(name, ino)| (*ino, self.file_type(*ino), name.clone())),
+        );
+        Ok(entries)
+    }
+
+    /// Value of the extended attribute `name` of `ino`.
+    fn xattr_value(&mut self, ino: u64, name: &OsStr) -> Result<String, c_int> {
+        let num = self.xattr_num(ino).map_err(|e| Self::xattr_errno(&e))?.ok_or(ENODATA)?;
+        let meta = self.storage.get_meta(num).map_err(|e| Self::xattr_errno(&e))?;
+        Self::xattr(&meta, name).ok_or(ENODATA)
+    }
+
+    /// Names of the extended attributes of `ino`, each terminated by a zero byte.
+    fn xattr_names(&mut self, ino: u64) -> Result<Vec<u8>, c_int> {
+        match self.xattr_num(ino).map_err(|e| Self::xattr_errno(&e))? {
+            Some(_) => Ok(Self::XATTRS.iter().flat_map(|name| name.bytes().chain([0])).collect()),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+impl<S: Storage> Filesystem for XkcdFS<S> {
+    fn init(&mut self, _req: &Request<'_>, _config: &mut KernelConfig) -> Result<(), c_int> {
+        if !self.inodes.contains_key(&Self::ROOT_INO) {
+            return Err(255);
+        }
+        self.load_root();
+        Ok(())
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        info!("lookup: parent = {}, name = {:?}", parent, name);
+        match self.lookup_entry(parent, name) {
+            Ok((ttl, attr)) => reply.entry(&ttl, &attr, 0),
+            Err(errno) => reply.error(errno),
+        }
     }
 
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
         info!("getattr: ino = {}", ino);
-        let Some(attr) = self.inodes.get(&ino).map(|inode| inode.attrs) else {
-            reply.error(ENOENT);
-            return;
-        };
-        reply.attr(&self.ttl, &attr);
+        match self.attr(ino) {
+            Ok((ttl, attr)) => reply.attr(&ttl, &attr),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        info!("readlink: ino = {}", ino);
+        match self.link_target(ino) {
+            Ok(target) => reply.data(target.as_bytes()),
+            Err(errno) => reply.error(errno),
+        }
     }
 
     fn read(
@@ -240,97 +766,287 @@ impl<S: Storage> Filesystem for XkcdFS<S> {
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        let offset = offset as u64;
         info!("read: ino = {}, offset = {}, size = {}", ino, offset, size);
+        match self.read_data(ino, offset as u64, size) {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        }
+    }
 
-        let Some(inode) = self.inodes.get(&ino) else {
-            warn!("ino not found");
-            reply.error(ENOENT);
-            return;
-        };
+    fn getxattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        info!("getxattr: ino = {}, name = {:?}", ino, name);
+        match self.xattr_value(ino, name) {
+            Ok(value) => Self::reply_xattr(value.as_bytes(), size, reply),
+            Err(errno) => reply.error(errno),
+        }
+    }
 
-        match &inode.kind {
-            INodeKind::Directory(_) => {
-                reply.error(EISDIR);
-            }
-            INodeKind::File(file) => {
-                let read_size = min(size as u64, inode.attrs.size.saturating_sub(offset));
-                let slice = (offset as usize)..(offset + read_size) as usize;
-                match *file {
-                    XkcdFile::Image(num) => {
-                        let Some(image) = self.storage.get_image(num) else {
-                            reply.error(ENOENT);
-                            return;
-                        };
-                        reply.data(&image[slice]);
-                    }
-                    XkcdFile::Num(num)
-                    | XkcdFile::Title(num)
-                    | XkcdFile::Alt(num)
-                    | XkcdFile::Transcript(num)
-                    | XkcdFile::ReleaseDate(num) => {
-                        if let Some(meta) = self.storage.get_meta(num) {
-                            let data = match *file {
-                                XkcdFile::Num(_) => meta.num.to_string(),
-                                XkcdFile::Title(_) => meta.title.clone(),
-                                XkcdFile::Alt(_) => meta.alt.clone(),
-                                XkcdFile::Transcript(_) => meta.transcript.clone(),
-                                XkcdFile::ReleaseDate(_) => meta.release_date.to_string(),
-                                _ => unreachable!(),
-                            };
-                            reply.data(data[slice].as_bytes());
-                        } else {
-                            reply.error(ENOENT);
-                        }
-                    }
-                }
-            }
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        info!("listxattr: ino = {}", ino);
+        match self.xattr_names(ino) {
+            Ok(names) => Self::reply_xattr(&names, size, reply),
+            Err(errno) => reply.error(errno),
         }
     }
 
     fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
         info!("readdir: ino = {}, offset = {}", ino, offset);
-
-        let Some(inode) = self.inodes.get(&ino) else {
-            reply.error(ENOENT);
-            return;
+        let entries = match self.dir_entries(ino) {
+            Ok(entries) => entries,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
         };
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
 
-        match &inode.kind {
-            INodeKind::Directory(dir) => {
-                if offset <= 0
-                    && reply.add(
-                        inode.attrs.ino,
-                        (inode.attrs.ino + 1) as i64,
-                        FileType::Directory,
-                        OsStr::new("."),
-                    )
-                {
-                    reply.ok();
-                    return;
-                };
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
 
-                if offset <= 1
-                    && let Some(parent) = dir.parent
-                    && reply.add(parent, 1, FileType::Directory, OsStr::new(".."))
-                {
-                    reply.ok();
-                    return;
-                };
+    use chrono::NaiveDate;
 
-                for (i, (name, ino)) in dir.children.iter().enumerate().skip(offset as usize) {
-                    let child = self.inodes.get(ino).unwrap();
-                    let kind = match &child.kind {
-                        INodeKind::Directory(_) => FileType::Directory,
-                        INodeKind::File(_) => FileType::RegularFile,
-                    };
-                    if reply.add(*ino, (i + 1) as i64, kind, name) {
-                        break;
-                    }
-                }
-                reply.ok();
-            }
-            INodeKind::File(_) => reply.error(ENOENT),
+    use super::*;
+    use crate::storage::BlockingXkcdStorage;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n0123456789";
+
+    /// Comics 1 to 3 in memory, all but the last with an image.
+    fn xkcd_fs() -> XkcdFS<BlockingXkcdStorage> {
+        let storage = BlockingXkcdStorage::memory();
+        for (num, title) in [(1, "Barrel"), (2, "Petit Trees"), (3, "Island")] {
+            storage
+                .insert_meta(&Xkcd::sample(num, title, &format!("Alt of {title}")))
+                .unwrap();
+        }
+        for num in [1, 2] {
+            storage
+                .insert_image(num, ImageVariant::Standard, PNG, ImageFormat::Png)
+                .unwrap();
+        }
+        let mut xkcd_fs = XkcdFS::new(storage);
+        xkcd_fs.load_root();
+        xkcd_fs
+    }
+
+    fn lookup_path(xkcd_fs: &mut XkcdFS<BlockingXkcdStorage>, path: &str) -> Result<FileAttr, c_int> {
+        let mut attr = xkcd_fs.attr(XkcdFS::<BlockingXkcdStorage>::ROOT_INO)?.1;
+        for name in path.split('/') {
+            attr = xkcd_fs.lookup_entry(attr.ino, OsStr::new(name))?.1;
+        }
+        Ok(attr)
+    }
+
+    fn names(xkcd_fs: &mut XkcdFS<BlockingXkcdStorage>, ino: u64) -> Vec<String> {
+        xkcd_fs
+            .dir_entries(ino)
+            .unwrap()
+            .into_iter()
+            .map(|(_, _, name)| name.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn root_lists_stored_comics() {
+        let mut xkcd_fs = xkcd_fs();
+        assert_eq!(names(&mut xkcd_fs, XkcdDir::Root.inode()), [
+            ".", "by-date", "search", "latest", "random", "xkcd_1", "xkcd_2", "xkcd_3"
+        ]);
+        assert_eq!(lookup_path(&mut xkcd_fs, "xkcd_4"), Err(ENOENT));
+        assert_eq!(lookup_path(&mut xkcd_fs, "xkcd_1/xkcd_2.title"), Err(ENOENT));
+    }
+
+    #[test]
+    fn comic_directory_is_built_on_lookup() {
+        let mut xkcd_fs = xkcd_fs();
+        let dir = lookup_path(&mut xkcd_fs, "xkcd_1").unwrap();
+        assert_eq!(dir.kind, FileType::Directory);
+        assert_eq!(names(&mut xkcd_fs, dir.ino), [
+            ".",
+            "..",
+            "xkcd_1.png",
+            "xkcd_1.num",
+            "xkcd_1.title",
+            "xkcd_1.alt",
+            "xkcd_1.transcript",
+            "xkcd_1.release_date",
+            "info.json"
+        ]);
+        // Without a stored image there is nothing to tell the format from but the URL.
+        assert_eq!(lookup_path(&mut xkcd_fs, "xkcd_3/xkcd_3.png").unwrap().size, 0);
+    }
+
+    #[test]
+    fn read_text_and_images() {
+        let mut xkcd_fs = xkcd_fs();
+        let title = lookup_path(&mut xkcd_fs, "xkcd_2/xkcd_2.title").unwrap();
+        assert_eq!(title.size, 11);
+        assert_eq!(xkcd_fs.read_data(title.ino, 0, 4096).unwrap(), b"Petit Trees");
+        assert_eq!(xkcd_fs.read_data(title.ino, 6, 3).unwrap(), b"Tre");
+
+        let image = lookup_path(&mut xkcd_fs, "xkcd_2/xkcd_2.png").unwrap();
+        assert_eq!(image.size, PNG.len() as u64);
+        assert_eq!(xkcd_fs.read_data(image.ino, 0, 4096).unwrap(), PNG);
+        assert_eq!(xkcd_fs.read_data(image.ino, 8, 4).unwrap(), b"0123");
+
+        let dir = lookup_path(&mut xkcd_fs, "xkcd_2").unwrap();
+        assert_eq!(xkcd_fs.read_data(dir.ino, 0, 10), Err(EISDIR));
+    }
+
+    #[test]
+    fn read_text_changed_since_the_directory_was_built() {
+        let mut xkcd_fs = xkcd_fs();
+        let title = lookup_path(&mut xkcd_fs, "xkcd_2/xkcd_2.title").unwrap();
+        assert_eq!(xkcd_fs.read_data(title.ino, 20, 10).unwrap(), b"");
+
+        xkcd_fs.storage.insert_meta(&Xkcd::sample(2, "Bäume", "")).unwrap();
+        assert_eq!(xkcd_fs.read_data(title.ino, 0, 11).unwrap(), "Bäume".as_bytes());
+        assert_eq!(xkcd_fs.attr(title.ino).unwrap().1.size, 6);
+        // Offsets are in bytes and may split a character.
+        assert_eq!(xkcd_fs.read_data(title.ino, 2, 2).unwrap(), b"\xa4u");
+        assert_eq!(xkcd_fs.read_data(title.ino, 8, 4).unwrap(), b"");
+    }
+
+    #[test]
+    fn updates_add_new_and_rebuild_changed_comics() {
+        let (updates, receiver) = mpsc::channel();
+        let mut xkcd_fs = xkcd_fs().with_updates(receiver);
+        let image = lookup_path(&mut xkcd_fs, "xkcd_1/xkcd_1.png").unwrap();
+        assert_eq!(image.size, PNG.len() as u64);
+
+        xkcd_fs
+            .storage
+            .insert_image(1, ImageVariant::Standard, b"GIF89a;", ImageFormat::Gif)
+            .unwrap();
+        xkcd_fs.storage.insert_meta(&Xkcd::sample(4, "Landscape", "")).unwrap();
+        updates.send(1).unwrap();
+        updates.send(4).unwrap();
+
+        assert_eq!(lookup_path(&mut xkcd_fs, "xkcd_1/xkcd_1.png"), Err(ENOENT));
+        let image = lookup_path(&mut xkcd_fs, "xkcd_1/xkcd_1.gif").unwrap();
+        assert_eq!(image.size, 7);
+        assert_eq!(xkcd_fs.read_data(image.ino, 0, 4096).unwrap(), b"GIF89a;");
+        assert_eq!(lookup_path(&mut xkcd_fs, "xkcd_4").unwrap().kind, FileType::Directory);
+        assert_eq!(xkcd_fs.link_target(XkcdLink::Latest.inode()).unwrap(), "xkcd_4");
+    }
+
+    #[test]
+    fn by_date_tree() {
+        let mut xkcd_fs = xkcd_fs();
+        let month = lookup_path(&mut xkcd_fs, "by-date/2008/01").unwrap();
+        assert_eq!(names(&mut xkcd_fs, month.ino), [
+            ".", "..", "xkcd_1", "xkcd_2", "xkcd_3"
+        ]);
+        let title = lookup_path(&mut xkcd_fs, "by-date/2008/01/xkcd_3/xkcd_3.title").unwrap();
+        assert_eq!(xkcd_fs.read_data(title.ino, 0, 4096).unwrap(), b"Island");
+    }
+
+    #[test]
+    fn by_date_tree_follows_changed_release_dates() {
+        let (updates, receiver) = mpsc::channel();
+        let mut xkcd_fs = xkcd_fs().with_updates(receiver);
+        lookup_path(&mut xkcd_fs, "by-date/2008/01").unwrap();
+
+        for date in [(2009, 5, 1), (2010, 1, 1)] {
+            let mut meta = Xkcd::sample(3, "Island", "");
+            meta.release_date = NaiveDate::from_ymd_opt(date.0, date.1, date.2).unwrap();
+            xkcd_fs.storage.insert_meta(&meta).unwrap();
+            updates.send(3).unwrap();
+            let month = lookup_path(&mut xkcd_fs, &format!("by-date/{}/{:02}", date.0, date.1)).unwrap();
+            assert_eq!(names(&mut xkcd_fs, month.ino), [".", "..", "xkcd_3"]);
+        }
+        let month = lookup_path(&mut xkcd_fs, "by-date/2008/01").unwrap();
+        assert_eq!(names(&mut xkcd_fs, month.ino), [".", "..", "xkcd_1", "xkcd_2"]);
+        assert_eq!(names(&mut xkcd_fs, XkcdDir::ByDate.inode()), [
+            ".", "..", "2008", "2010"
+        ]);
+        assert_eq!(lookup_path(&mut xkcd_fs, "by-date/2009"), Err(ENOENT));
+    }
+
+    #[test]
+    fn search_keeps_the_most_recent_queries() {
+        let mut xkcd_fs = xkcd_fs();
+        let query = lookup_path(&mut xkcd_fs, "search/alt trees").unwrap();
+        assert_eq!(names(&mut xkcd_fs, query.ino), [".", "..", "xkcd_2"]);
+
+        for i in 0..XkcdFS::<BlockingXkcdStorage>::MAX_QUERIES * 2 {
+            lookup_path(&mut xkcd_fs, &format!("search/query {i}")).unwrap();
+        }
+        let search = names(&mut xkcd_fs, XkcdDir::Search.inode());
+        assert_eq!(search.len(), 2 + XkcdFS::<BlockingXkcdStorage>::MAX_QUERIES);
+        assert_eq!(
+            search.last().unwrap(),
+            &format!("query {}", XkcdFS::<BlockingXkcdStorage>::MAX_QUERIES * 2 - 1)
+        );
+        assert!(!search.contains(&"alt trees".to_string()));
+        assert_eq!(xkcd_fs.attr(query.ino), Err(ENOENT));
+    }
+
+    #[test]
+    fn latest_and_random_links() {
+        let mut xkcd_fs = xkcd_fs();
+        let latest = lookup_path(&mut xkcd_fs, "latest").unwrap();
+        assert_eq!(latest.kind, FileType::Symlink);
+        assert_eq!(xkcd_fs.link_target(latest.ino).unwrap(), "xkcd_3");
+
+        let (ttl, random) = xkcd_fs
+            .lookup_entry(XkcdDir::Root.inode(), OsStr::new("random"))
+            .unwrap();
+        assert_eq!(ttl, Duration::ZERO);
+        let target = xkcd_fs.link_target(random.ino).unwrap();
+        assert!(["xkcd_1", "xkcd_2", "xkcd_3"].contains(&target.as_str()));
+        assert_eq!(random.size, target.len() as u64);
+
+        let dir = lookup_path(&mut xkcd_fs, "xkcd_1").unwrap();
+        assert_eq!(xkcd_fs.link_target(dir.ino), Err(EINVAL));
+    }
+
+    #[test]
+    fn xattrs_on_comic_directories_and_images() {
+        let mut xkcd_fs = xkcd_fs();
+        let dir = lookup_path(&mut xkcd_fs, "xkcd_1").unwrap();
+        let image = lookup_path(&mut xkcd_fs, "xkcd_1/xkcd_1.png").unwrap();
+        for ino in [dir.ino, image.ino] {
+            assert_eq!(
+                xkcd_fs.xattr_value(ino, OsStr::new("user.xkcd.title")).unwrap(),
+                "Barrel"
+            );
+            assert_eq!(xkcd_fs.xattr_value(ino, OsStr::new("user.xkcd.num")).unwrap(), "1");
+            assert_eq!(xkcd_fs.xattr_value(ino, OsStr::new("user.other")), Err(ENODATA));
+            assert_eq!(xkcd_fs.xattr_names(ino).unwrap().iter().filter(|b| **b == 0).count(), 5);
+        }
+
+        let title = lookup_path(&mut xkcd_fs, "xkcd_1/xkcd_1.title").unwrap();
+        assert_eq!(
+            xkcd_fs.xattr_value(title.ino, OsStr::new("user.xkcd.title")),
+            Err(ENODATA)
+        );
+        assert_eq!(xkcd_fs.xattr_names(title.ino).unwrap(), b"");
+
+        let corrupted = StorageError::Corrupted("bad JSON".to_string());
+        assert_eq!(XkcdFS::<BlockingXkcdStorage>::xattr_errno(&corrupted), EIO);
+        assert_eq!(
+            XkcdFS::<BlockingXkcdStorage>::xattr_errno(&StorageError::NotFound),
+            ENOENT
+        );
+    }
+
+    #[test]
+    fn known_gaps_are_not_fetched_on_access() {
+        let storage = BlockingXkcdStorage::memory();
+        for num in [1, 3] {
+            storage.insert_meta(&Xkcd::sample(num, "Title", "Alt")).unwrap();
         }
+        storage.insert_gap(2);
+        let mut xkcd_fs = XkcdFS::new(storage).with_fetch_on_access();
+        xkcd_fs.load_root();
+        assert_eq!(lookup_path(&mut xkcd_fs, "xkcd_2"), Err(ENOENT));
     }
 }
@@ -0,0 +1,153 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ImageVariant {
+    Standard,
+    /// High-resolution `_2x` image, available for most modern comics.
+    Double,
+}
+
+impl ImageVariant {
+    pub const fn scale(&self) -> u32 {
+        match self {
+            ImageVariant::Standard => 1,
+            ImageVariant::Double => 2,
+        }
+    }
+
+    /// Inserts the `_2x` suffix before the extension of the image file name.
+    pub fn file_name(&self, name: &str) -> String {
+        match (self, name.rsplit_once('.')) {
+            (ImageVariant::Standard, _) => name.to_string(),
+            (ImageVariant::Double, Some((stem, extension))) => format!("{stem}_2x.{extension}"),
+            (ImageVariant::Double, None) => format!("{name}_2x"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Gif,
+}
+
+impl ImageFormat {
+    const ALL: [ImageFormat; 3] = [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::Gif];
+
+    /// Detects the format from the magic number at the start of the image.
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        match data {
+            [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(ImageFormat::Png),
+            [0xFF, 0xD8, 0xFF, ..] => Some(ImageFormat::Jpeg),
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(ImageFormat::Gif),
+            _ => None,
+        }
+    }
+
+    pub fn from_url(url: &str) -> Option<Self> {
+        let (_, extension) = url.rsplit_once('.')?;
+        let extension = extension.to_ascii_lowercase();
+        match extension.as_str() {
+            "jpeg" => Some(ImageFormat::Jpeg),
+            _ => Self::ALL.into_iter().find(|format| format.extension() == extension),
+        }
+    }
+
+    pub fn from_mime_type(mime_type: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.mime_type() == mime_type)
+    }
+
+    /// Whether `data` ends with the trailer of the format, which a cut-off download does not.
+    pub fn is_complete(&self, data: &[u8]) -> bool {
+        match self {
+            ImageFormat::Png => data.len() >= 8 && &data[data.len() - 8..data.len() - 4] == b"IEND",
+            // Some encoders pad the file after the end-of-image marker.
+            ImageFormat::Jpeg => data.windows(2).rev().take(32).any(|end| end == [0xFF, 0xD9]),
+            ImageFormat::Gif => data.ends_with(&[0x3B]),
+        }
+    }
+
+    /// Format of a downloaded image, falling back to the extension in its URL and then to PNG.
+    pub fn detect(data: &[u8], url: &str) -> Self {
+        Self::sniff(data)
+            .or_else(|| Self::from_url(url))
+            .unwrap_or(ImageFormat::Png)
+    }
+
+    pub const fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Gif => "image/gif",
+        }
+    }
+
+    pub const fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Gif => "gif",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const JPEG: &[u8] = b"\xff\xd8\xff\xe0\0\x10JFIF";
+    const GIF: &[u8] = b"GIF89a\x01\0\x01\0";
+
+    #[test]
+    fn sniff_magic_numbers() {
+        assert_eq!(ImageFormat::sniff(PNG), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::sniff(JPEG), Some(ImageFormat::Jpeg));
+        assert_eq!(ImageFormat::sniff(GIF), Some(ImageFormat::Gif));
+        assert_eq!(ImageFormat::sniff(b"GIF87a"), Some(ImageFormat::Gif));
+        assert_eq!(ImageFormat::sniff(b"GIF88a"), None);
+        assert_eq!(ImageFormat::sniff(&PNG[..7]), None);
+        assert_eq!(ImageFormat::sniff(b"<html>"), None);
+        assert_eq!(ImageFormat::sniff(&[]), None);
+    }
+
+    #[test]
+    fn detect_prefers_content_over_url() {
+        let url = "https://imgs.xkcd.com/comics/barrel_cropped_(1).jpg";
+        assert_eq!(ImageFormat::detect(PNG, url), ImageFormat::Png);
+        assert_eq!(ImageFormat::detect(b"", url), ImageFormat::Jpeg);
+        assert_eq!(
+            ImageFormat::detect(b"", "https://imgs.xkcd.com/comics/a.JPEG"),
+            ImageFormat::Jpeg
+        );
+        assert_eq!(ImageFormat::detect(b"", "https://xkcd.com/"), ImageFormat::Png);
+    }
+
+    #[test]
+    fn is_complete_checks_trailers() {
+        let png = [PNG, b"\0\0\0\0IEND\xaeB`\x82"].concat();
+        assert!(ImageFormat::Png.is_complete(&png));
+        assert!(!ImageFormat::Png.is_complete(&png[..png.len() - 1]));
+        assert!(!ImageFormat::Png.is_complete(b"IEND"));
+
+        let jpeg = [JPEG, b"\xff\xd9"].concat();
+        assert!(ImageFormat::Jpeg.is_complete(&jpeg));
+        assert!(ImageFormat::Jpeg.is_complete(&[jpeg.as_slice(), &[0; 16]].concat()));
+        assert!(!ImageFormat::Jpeg.is_complete(&[jpeg.as_slice(), &[0; 64]].concat()));
+        assert!(!ImageFormat::Jpeg.is_complete(JPEG));
+
+        assert!(ImageFormat::Gif.is_complete(&[GIF, b";"].concat()));
+        assert!(!ImageFormat::Gif.is_complete(GIF));
+
+        for format in ImageFormat::ALL {
+            assert!(!format.is_complete(&[]));
+        }
+    }
+
+    #[test]
+    fn mime_type_round_trip() {
+        for format in ImageFormat::ALL {
+            assert_eq!(ImageFormat::from_mime_type(format.mime_type()), Some(format));
+        }
+        assert_eq!(ImageFormat::from_mime_type("image/webp"), None);
+    }
+}
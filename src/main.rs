@@ -1,20 +1,29 @@
 #![feature(let_chains)]
 
-use std::io::Write;
+use std::{io::Write, path::Path, time::Duration};
 
 use clap::Parser;
 use log::LevelFilter;
 
 use crate::{
-    cli::Cli,
-    storage::{BlockingXkcdStorage, XkcdStorage, XkcdStorageConfig},
+    api::RetryPolicy,
+    cli::{Cli, Command, ExportFormat, Field},
+    dump::DumpImages,
+    export::ExportFilter,
+    fs::live_update::LiveUpdate,
+    image::ImageVariant,
+    storage::{BackendKind, BlockingXkcdStorage, CachedStorage, Storage, XkcdStorage, XkcdStorageConfig},
 };
 
 mod api;
 mod cli;
 mod db;
+mod dump;
+mod export;
 mod fs;
+mod image;
 mod storage;
+mod verify;
 mod xkcd;
 
 fn init_logger() {
@@ -26,13 +35,205 @@ fn init_logger() {
         .unwrap();
 }
 
+fn get(storage: &BlockingXkcdStorage, num: u32, field: Option<Field>) -> Result<(), ()> {
+    let meta = storage.get_meta(num).map_err(|e| eprintln!("xkcd {num}: {e}"))?;
+    let value = match field {
+        None => {
+            println!("num: {}", meta.num);
+            println!("title: {}", meta.title);
+            println!("safe_title: {}", meta.safe_title);
+            println!("alt: {}", meta.alt);
+            println!("transcript: {}", meta.transcript);
+            println!("link: {}", meta.link);
+            println!("image_url: {}", meta.image_url);
+            println!("release_date: {}", meta.release_date);
+            println!("news: {}", meta.news);
+            return Ok(());
+        }
+        Some(field @ (Field::Image | Field::Image2x)) => {
+            let variant = match field {
+                Field::Image2x => ImageVariant::Double,
+                _ => ImageVariant::Standard,
+            };
+            let image = storage
+                .get_image(num, variant)
+                .map_err(|e| eprintln!("{variant:?} image for xkcd {num}: {e}"))?;
+            return std::io::stdout()
+                .write_all(&image)
+                .map_err(|e| eprintln!("Failed to write image: {e}"));
+        }
+        Some(Field::Num) => meta.num.to_string(),
+        Some(Field::Title) => meta.title,
+        Some(Field::SafeTitle) => meta.safe_title,
+        Some(Field::Alt) => meta.alt,
+        Some(Field::Transcript) => meta.transcript,
+        Some(Field::Link) => meta.link,
+        Some(Field::ImageUrl) => meta.image_url,
+        Some(Field::ReleaseDate) => meta.release_date.to_string(),
+        Some(Field::News) => meta.news,
+        Some(Field::Json) => meta.to_json(),
+    };
+    println!("{value}");
+    Ok(())
+}
+
+fn search(storage: &BlockingXkcdStorage, query: &str) -> Result<(), ()> {
+    let results = storage.search(query).map_err(|e| eprintln!("Search failed: {e}"))?;
+    for meta in results.into_iter().filter_map(|num| storage.get_meta(num).ok()) {
+        println!("{}: {}", meta.num, meta.title);
+    }
+    Ok(())
+}
+
+fn stats(storage: &BlockingXkcdStorage) -> Result<(), ()> {
+    let stats = storage.get_stats().map_err(|e| eprintln!("Failed to get stats: {e}"))?;
+    println!("Comics stored: {}", stats.comics);
+    println!("Images stored: {}", stats.images);
+    if let (Some(first), Some(last)) = (stats.first, stats.last) {
+        let gaps = stats.gaps.iter().filter(|num| (first..=last).contains(*num)).count() as u32;
+        println!("Range: {first}-{last}");
+        // A gap that was released after all is counted both as stored and as a gap.
+        let missing = (last - first + 1).saturating_sub(stats.comics).saturating_sub(gaps);
+        println!("Missing in range: {missing}");
+    }
+    let gaps = stats.gaps.iter().map(u32::to_string).collect::<Vec<_>>();
+    println!(
+        "Known gaps: {}",
+        if gaps.is_empty() {
+            "none".to_string()
+        } else {
+            gaps.join(", ")
+        }
+    );
+    Ok(())
+}
+
+fn refresh(storage: &BlockingXkcdStorage, start: u32, end: u32, images: bool) -> Result<(), ()> {
+    let report = storage
+        .refresh(start, end, images)
+        .map_err(|e| eprintln!("Refresh failed: {e}"))?;
+    for num in &report.changed {
+        println!("xkcd {num} changed");
+    }
+    for (num, variant) in &report.changed_images {
+        println!("xkcd {num} ({variant:?} image) changed");
+    }
+    println!(
+        "Checked {} comics, {} changed, {} images changed, {} failed",
+        report.checked,
+        report.changed.len(),
+        report.changed_images.len(),
+        report.failed
+    );
+    if report.failed > 0 {
+        return Err(());
+    }
+    Ok(())
+}
+
+fn verify(storage: &BlockingXkcdStorage, repair: bool) -> Result<(), ()> {
+    let report = verify::verify(storage, repair).map_err(|e| eprintln!("Verification failed: {e:#}"))?;
+    println!(
+        "Checked {} images, {} broken, {} fetched again",
+        report.checked, report.corrupt, report.repaired
+    );
+    if report.corrupt > report.repaired {
+        return Err(());
+    }
+    Ok(())
+}
+
+fn export(storage: &BlockingXkcdStorage, format: ExportFormat, output: &Path, filter: &ExportFilter) -> Result<(), ()> {
+    let comics = export::select(storage, filter).map_err(|e| eprintln!("Failed to list stored comics: {e}"))?;
+    let exported = match format {
+        ExportFormat::Cbz => export::cbz::export(storage, &comics, output),
+        ExportFormat::Html => export::html::export(storage, &comics, output),
+    }
+    .map_err(|e| eprintln!("Export failed: {e:#}"))?;
+    println!("Exported {exported} comics to {}", output.display());
+    Ok(())
+}
+
 fn main() -> Result<(), ()> {
     init_logger();
     let cli = Cli::parse();
 
-    let storage: XkcdStorage = XkcdStorageConfig { db_path: cli.db_path }.into();
+    let config = XkcdStorageConfig {
+        backend: cli.backend,
+        db_path: cli.db_path,
+        base_url: cli.base_url,
+        image_base_url: cli.image_base_url,
+        retry_policy: RetryPolicy {
+            max_attempts: cli.max_attempts,
+            ..Default::default()
+        },
+        offline: cli.offline,
+        fetch_2x: cli.fetch_2x,
+    };
+    let storage: XkcdStorage = config.clone().into();
     let blocking_storage: BlockingXkcdStorage = storage.into();
-    blocking_storage.ensure_range(cli.start, cli.end)?;
-    fs::fuse(cli.mount_point.as_path(), blocking_storage);
+    match cli.command {
+        Command::Mount {
+            mount_point,
+            range,
+            update_interval,
+            cache_size,
+            fetch_on_access,
+        } => {
+            blocking_storage.ensure_range(range.start, range.end)?;
+            if update_interval.is_some() && config.backend == BackendKind::Memory {
+                eprintln!("Live update needs a persistent backend");
+                return Err(());
+            }
+            let Some(cache_bytes) = cache_size.checked_mul(1 << 20) else {
+                eprintln!("Cache size of {cache_size} MB is too large");
+                return Err(());
+            };
+            let live_update = update_interval.map(|secs| LiveUpdate {
+                storage: XkcdStorage::from(config).into(),
+                interval: Duration::from_secs(secs),
+            });
+            if cache_size > 0 {
+                let cached_storage = CachedStorage::new(blocking_storage, cache_bytes);
+                fs::fuse(mount_point.as_path(), cached_storage, live_update, fetch_on_access);
+            } else {
+                fs::fuse(mount_point.as_path(), blocking_storage, live_update, fetch_on_access);
+            }
+        }
+        Command::Sync { range } => blocking_storage.ensure_range(range.start, range.end)?,
+        Command::Get { num, field } => get(&blocking_storage, num, field)?,
+        Command::Search { query } => search(&blocking_storage, &query)?,
+        Command::Stats => stats(&blocking_storage)?,
+        Command::Refresh { start, end, images } => {
+            refresh(&blocking_storage, start.unwrap_or(0), end.unwrap_or(u32::MAX), images)?
+        }
+        Command::Verify { repair } => verify(&blocking_storage, repair)?,
+        Command::Dump {
+            output,
+            inline_images,
+            image_dir,
+        } => {
+            let images = match (inline_images, &image_dir) {
+                (true, _) => DumpImages::Inline,
+                (false, Some(dir)) => DumpImages::Dir(dir),
+                (false, None) => DumpImages::None,
+            };
+            let dumped = dump::dump(&blocking_storage, &output, images).map_err(|e| eprintln!("Dump failed: {e:#}"))?;
+            println!("Dumped {dumped} comics to {}", output.display());
+        }
+        Command::Restore { input, image_dir } => {
+            let image_dir = image_dir.unwrap_or_else(|| input.parent().unwrap_or(Path::new(".")).to_path_buf());
+            let restored =
+                dump::restore(&blocking_storage, &input, &image_dir).map_err(|e| eprintln!("Restore failed: {e:#}"))?;
+            println!("Restored {restored} comics from {}", input.display());
+        }
+        Command::Export {
+            format,
+            output,
+            start,
+            end,
+            year,
+        } => export(&blocking_storage, format, &output, &ExportFilter { start, end, year })?,
+    }
     Ok(())
 }
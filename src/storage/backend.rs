@@ -0,0 +1,143 @@
+use std::fmt::Debug;
+
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    api::Validators,
+    image::{ImageFormat, ImageVariant},
+    xkcd::Xkcd,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(ValueEnum)]
+pub enum BackendKind {
+    /// Single SQLite database file
+    Sqlite,
+    /// Plain directory with one folder per comic
+    Dir,
+    /// Nothing is persisted, everything is lost on exit
+    Memory,
+}
+
+#[derive(Debug)]
+pub struct Stats {
+    pub comics: u32,
+    pub images: u32,
+    pub first: Option<u32>,
+    pub last: Option<u32>,
+    pub gaps: Vec<u32>,
+}
+
+/// An image as stored, with what was recorded about it when it was inserted.
+#[derive(Debug)]
+pub struct ImageRecord {
+    pub data: Vec<u8>,
+    pub size: u64,
+    pub format: Option<ImageFormat>,
+    /// SHA-256 of the data as inserted, for backends that keep one.
+    pub sha256: Option<String>,
+}
+
+/// Something that is fetched separately: the JSON of a comic or one of its images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Meta,
+    Image(ImageVariant),
+}
+
+/// Local persistence for fetched comics. Lookups return `Ok(None)` for anything that is not stored.
+pub trait Backend: Debug + Send {
+    fn get_stored_ids(&self) -> anyhow::Result<Vec<u32>>;
+    fn get_meta(&self, num: u32) -> anyhow::Result<Option<Xkcd>>;
+    /// Returns whether the comic is new or differs from the stored one.
+    fn insert_meta(&self, xkcd: &Xkcd) -> anyhow::Result<bool>;
+    fn get_image(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<Vec<u8>>>;
+    /// Up to `len` bytes of the image starting at `offset`, without loading the rest where the backend allows it.
+    fn read_image(&self, num: u32, variant: ImageVariant, offset: u64, len: usize) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self
+            .get_image(num, variant)?
+            .map(|data| slice(&data, offset, len).to_vec()))
+    }
+    fn get_image_size(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<u64>>;
+    fn get_image_format(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<ImageFormat>>;
+    fn insert_image(&self, num: u32, variant: ImageVariant, data: &[u8], format: ImageFormat) -> anyhow::Result<()>;
+    fn get_image_record(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<ImageRecord>> {
+        let Some(data) = self.get_image(num, variant)? else {
+            return Ok(None);
+        };
+        Ok(Some(ImageRecord {
+            size: self.get_image_size(num, variant)?.unwrap_or(data.len() as u64),
+            format: self.get_image_format(num, variant)?,
+            sha256: None,
+            data,
+        }))
+    }
+    /// Validators of the last response for `resource`, for backends that keep them.
+    fn get_validators(&self, _num: u32, _resource: Resource) -> anyhow::Result<Option<Validators>> { Ok(None) }
+    fn set_validators(&self, _num: u32, _resource: Resource, _validators: &Validators) -> anyhow::Result<()> { Ok(()) }
+    /// Comics stored or changed at or after the unix timestamp `since`, possibly by another process. Backends that
+    /// cannot tell report none.
+    fn changed_since(&self, _since: i64) -> anyhow::Result<Vec<u32>> { Ok(vec![]) }
+    fn get_gaps(&self) -> anyhow::Result<Vec<u32>>;
+    fn insert_gap(&self, num: u32) -> anyhow::Result<()>;
+    /// Comics known to have no `_2x` image upstream.
+    fn get_missing_2x(&self) -> anyhow::Result<Vec<u32>>;
+    fn insert_missing_2x(&self, num: u32) -> anyhow::Result<()>;
+    fn search(&self, query: &str) -> anyhow::Result<Vec<u32>>;
+    fn get_stats(&self) -> anyhow::Result<Stats>;
+}
+
+/// Search for backends without a full-text index: every word of the query has to occur in the title, alt text or
+/// transcript, comics with more occurrences come first.
+pub fn search_metas(metas: impl IntoIterator<Item = Xkcd>, query: &str) -> Vec<u32> {
+    let words = query.split_whitespace().map(str::to_lowercase).collect::<Vec<_>>();
+    if words.is_empty() {
+        return vec![];
+    }
+
+    let mut matches = metas
+        .into_iter()
+        .filter_map(|meta| {
+            let text = format!("{} {} {}", meta.title, meta.alt, meta.transcript).to_lowercase();
+            let counts = words
+                .iter()
+                .map(|word| text.matches(word.as_str()).count())
+                .collect::<Vec<_>>();
+            counts
+                .iter()
+                .all(|count| *count > 0)
+                .then(|| (meta.num, counts.iter().sum::<usize>()))
+        })
+        .collect::<Vec<_>>();
+    matches.sort_by(|(a_num, a_count), (b_num, b_count)| b_count.cmp(a_count).then(a_num.cmp(b_num)));
+    matches.into_iter().map(|(num, _)| num).collect()
+}
+
+/// The part of `data` a ranged read asks for, empty past the end.
+pub fn slice(data: &[u8], offset: u64, len: usize) -> &[u8] {
+    let start = usize::try_from(offset).unwrap_or(usize::MAX).min(data.len());
+    let end = start.saturating_add(len).min(data.len());
+    &data[start..end]
+}
+
+/// Lowercase hex SHA-256 of `data`.
+pub fn sha256_hex(data: &[u8]) -> String { Sha256::digest(data).iter().map(|byte| format!("{byte:02x}")).collect() }
+
+/// Stats for backends that do not keep them, computed from the stored ids.
+pub fn compute_stats(backend: &dyn Backend) -> anyhow::Result<Stats> {
+    let ids = backend.get_stored_ids()?;
+    let mut images = 0;
+    for num in &ids {
+        if backend.get_image_size(*num, ImageVariant::Standard)?.is_some() {
+            images += 1;
+        }
+    }
+    Ok(Stats {
+        comics: ids.len() as u32,
+        images,
+        first: ids.iter().min().copied(),
+        last: ids.iter().max().copied(),
+        gaps: backend.get_gaps()?,
+    })
+}
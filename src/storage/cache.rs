@@ -0,0 +1,286 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    mem::size_of,
+    sync::Mutex,
+};
+
+use log::info;
+
+use crate::{
+    image::{ImageFormat, ImageVariant},
+    storage::{Storage, StorageError, slice},
+    xkcd::Xkcd,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Meta(u32),
+    Image(u32, ImageVariant),
+}
+
+#[derive(Debug)]
+enum Value {
+    Meta(Xkcd),
+    Image(Vec<u8>),
+}
+
+impl Value {
+    /// Rough memory footprint, what the cache size is measured in.
+    fn size(&self) -> usize {
+        size_of::<Self>()
+            + match self {
+                Value::Meta(meta) => [
+                    &meta.title,
+                    &meta.safe_title,
+                    &meta.image_url,
+                    &meta.alt,
+                    &meta.transcript,
+                    &meta.link,
+                    &meta.news,
+                ]
+                .iter()
+                .map(|s| s.len())
+                .sum(),
+                Value::Image(data) => data.len(),
+            }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub size: usize,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    /// Value and the tick it was last used at.
+    entries: HashMap<Key, (Value, u64)>,
+    /// Keys by the tick they were last used at, least recently used first.
+    order: BTreeMap<u64, Key>,
+    tick: u64,
+    capacity: usize,
+    stats: CacheStats,
+}
+
+impl Lru {
+    fn get(&mut self, key: Key) -> Option<&Value> {
+        let Some((_, used)) = self.entries.get_mut(&key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        self.tick += 1;
+        self.order.remove(used);
+        self.order.insert(self.tick, key);
+        *used = self.tick;
+        self.entries.get(&key).map(|(value, _)| value)
+    }
+
+    fn insert(&mut self, key: Key, value: Value) {
+        let size = value.size();
+        if size > self.capacity {
+            return;
+        }
+        self.remove(key);
+        while self.stats.size + size > self.capacity
+            && let Some((_, oldest)) = self.order.pop_first()
+        {
+            if let Some((value, _)) = self.entries.remove(&oldest) {
+                self.stats.size -= value.size();
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key);
+        self.entries.insert(key, (value, self.tick));
+        self.stats.size += size;
+    }
+
+    fn remove(&mut self, key: Key) {
+        if let Some((value, used)) = self.entries.remove(&key) {
+            self.order.remove(&used);
+            self.stats.size -= value.size();
+        }
+    }
+}
+
+/// Keeps recently used comics and images of the wrapped storage in memory, up to `capacity` bytes.
+#[derive(Debug)]
+pub struct CachedStorage<S: Storage> {
+    storage: S,
+    lru: Mutex<Lru>,
+}
+
+impl<S: Storage> CachedStorage<S> {
+    pub fn new(storage: S, capacity: usize) -> Self {
+        Self {
+            storage,
+            lru: Mutex::new(Lru {
+                capacity,
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru = self.lru.lock().unwrap();
+        CacheStats {
+            entries: lru.entries.len(),
+            ..lru.stats
+        }
+    }
+}
+
+impl<S: Storage> Drop for CachedStorage<S> {
+    fn drop(&mut self) {
+        let stats = self.stats();
+        info!(
+            "Cache: {} hits, {} misses, {} entries, {} bytes",
+            stats.hits, stats.misses, stats.entries, stats.size
+        );
+    }
+}
+
+impl<S: Storage> Storage for CachedStorage<S> {
+    fn get_stored_ids(&self) -> Result<Vec<u32>, StorageError> { self.storage.get_stored_ids() }
+
+    fn get_meta(&self, num: u32) -> Result<Xkcd, StorageError> {
+        if let Some(Value::Meta(meta)) = self.lru.lock().unwrap().get(Key::Meta(num)) {
+            return Ok(meta.clone());
+        }
+        let meta = self.storage.get_meta(num)?;
+        self.lru
+            .lock()
+            .unwrap()
+            .insert(Key::Meta(num), Value::Meta(meta.clone()));
+        Ok(meta)
+    }
+
+    fn get_image(&self, num: u32, variant: ImageVariant) -> Result<Vec<u8>, StorageError> {
+        if let Some(Value::Image(data)) = self.lru.lock().unwrap().get(Key::Image(num, variant)) {
+            return Ok(data.clone());
+        }
+        let data = self.storage.get_image(num, variant)?;
+        self.lru
+            .lock()
+            .unwrap()
+            .insert(Key::Image(num, variant), Value::Image(data.clone()));
+        Ok(data)
+    }
+
+    /// Small images are loaded and cached whole on the first read; images too big to be worth caching are read in
+    /// ranges from the wrapped storage.
+    fn read_image(&self, num: u32, variant: ImageVariant, offset: u64, len: usize) -> Result<Vec<u8>, StorageError> {
+        if let Some(Value::Image(data)) = self.lru.lock().unwrap().get(Key::Image(num, variant)) {
+            return Ok(slice(data, offset, len).to_vec());
+        }
+        let capacity = self.lru.lock().unwrap().capacity;
+        match self.storage.get_image_size(num, variant) {
+            Ok(size) if size as usize <= capacity / 8 => {
+                let data = self.storage.get_image(num, variant)?;
+                let range = slice(&data, offset, len).to_vec();
+                self.lru
+                    .lock()
+                    .unwrap()
+                    .insert(Key::Image(num, variant), Value::Image(data));
+                Ok(range)
+            }
+            _ => self.storage.read_image(num, variant, offset, len),
+        }
+    }
+
+    fn get_xkcd(&self, num: u32) -> Result<(Xkcd, Vec<u8>), StorageError> { self.storage.get_xkcd(num) }
+
+    fn get_image_size(&self, num: u32, variant: ImageVariant) -> Result<u64, StorageError> {
+        self.storage.get_image_size(num, variant)
+    }
+
+    fn get_image_format(&self, num: u32, variant: ImageVariant) -> Result<ImageFormat, StorageError> {
+        self.storage.get_image_format(num, variant)
+    }
+
+    fn search(&self, query: &str) -> Result<Vec<u32>, StorageError> { self.storage.search(query) }
+
+    fn evict(&self, num: u32) {
+        let mut lru = self.lru.lock().unwrap();
+        for key in [
+            Key::Meta(num),
+            Key::Image(num, ImageVariant::Standard),
+            Key::Image(num, ImageVariant::Double),
+        ] {
+            lru.remove(key);
+        }
+        drop(lru);
+        self.storage.evict(num);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::BlockingXkcdStorage;
+
+    fn cached(capacity: usize) -> CachedStorage<BlockingXkcdStorage> {
+        let storage = BlockingXkcdStorage::memory();
+        for num in 1..=3 {
+            storage.insert_meta(&Xkcd::sample(num, "Barrel", "")).unwrap();
+            storage
+                .insert_image(num, ImageVariant::Standard, &[num as u8; 1000], ImageFormat::Png)
+                .unwrap();
+        }
+        CachedStorage::new(storage, capacity)
+    }
+
+    #[test]
+    fn least_recently_used_is_dropped_first() {
+        let image_size = Value::Image(vec![0; 1000]).size();
+        let cache = cached(2 * image_size);
+        cache.get_image(1, ImageVariant::Standard).unwrap();
+        cache.get_image(2, ImageVariant::Standard).unwrap();
+        cache.get_image(1, ImageVariant::Standard).unwrap();
+        cache.get_image(3, ImageVariant::Standard).unwrap();
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 3, 2));
+        assert!(stats.size <= 2 * image_size);
+        cache.get_image(1, ImageVariant::Standard).unwrap();
+        assert_eq!(cache.stats().hits, 2);
+        cache.get_image(2, ImageVariant::Standard).unwrap();
+        assert_eq!(cache.stats().misses, 4);
+    }
+
+    #[test]
+    fn ranged_reads_of_big_images_are_not_cached() {
+        let cache = cached(4000);
+        assert_eq!(cache.read_image(1, ImageVariant::Standard, 998, 10).unwrap(), [1, 1]);
+        assert_eq!(cache.stats().entries, 0);
+
+        let cache = cached(100_000);
+        assert_eq!(cache.read_image(1, ImageVariant::Standard, 10, 3).unwrap(), [1, 1, 1]);
+        assert_eq!(cache.stats().entries, 1);
+    }
+
+    #[test]
+    fn evict_drops_stale_entries() {
+        let cache = cached(100_000);
+        assert_eq!(cache.get_meta(1).unwrap().title, "Barrel");
+        cache.get_image(1, ImageVariant::Standard).unwrap();
+
+        cache
+            .storage
+            .insert_meta(&Xkcd::sample(1, "Barrel - Part 1", ""))
+            .unwrap();
+        cache
+            .storage
+            .insert_image(1, ImageVariant::Standard, b"new", ImageFormat::Png)
+            .unwrap();
+        assert_eq!(cache.get_meta(1).unwrap().title, "Barrel");
+
+        cache.evict(1);
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.get_meta(1).unwrap().title, "Barrel - Part 1");
+        assert_eq!(cache.get_image(1, ImageVariant::Standard).unwrap(), b"new");
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    process,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::{
+    image::{ImageFormat, ImageVariant},
+    storage::backend::{Backend, Stats, compute_stats, search_metas},
+    xkcd::Xkcd,
+};
+
+const INFO: &str = "info.json";
+const GAPS: &str = "gaps.json";
+const MISSING_2X: &str = "missing_2x.json";
+
+/// Plain directory store: `xkcd_N/info.json`, `xkcd_N/image.<ext>` and `xkcd_N/image_2x.<ext>` for every comic and
+/// `gaps.json` and `missing_2x.json` at the top. Files are written to a temporary name and renamed, so readers never
+/// see partial files.
+#[derive(Debug)]
+pub struct DirBackend {
+    root: PathBuf,
+}
+
+impl DirBackend {
+    pub fn open(root: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(root)?;
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn comic_dir(&self, num: u32) -> PathBuf { self.root.join(format!("xkcd_{num}")) }
+
+    const fn image_stem(variant: ImageVariant) -> &'static str {
+        match variant {
+            ImageVariant::Standard => "image",
+            ImageVariant::Double => "image_2x",
+        }
+    }
+
+    fn find_image(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<(PathBuf, ImageFormat)>> {
+        let entries = match fs::read_dir(self.comic_dir(num)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.file_stem().is_some_and(|stem| stem == Self::image_stem(variant))
+                && let Some(format) = path.to_str().and_then(ImageFormat::from_url)
+            {
+                return Ok(Some((path, format)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+        match fs::read(path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The temporary name is unique, so writers sharing the store do not write into each other's files.
+    fn write(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(".{}-{:08x}.tmp", process::id(), rand::random::<u32>()));
+        if let Err(e) = fs::write(&tmp, data).and_then(|()| fs::rename(&tmp, path)) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// The sorted numbers in the JSON list `name` at the top, empty if it does not exist.
+    fn read_nums(&self, name: &str) -> anyhow::Result<Vec<u32>> {
+        Self::read(&self.root.join(name))?
+            .map(|json| Ok(serde_json::from_slice(&json)?))
+            .unwrap_or(Ok(vec![]))
+    }
+
+    fn insert_num(&self, name: &str, num: u32) -> anyhow::Result<()> {
+        let mut nums = self.read_nums(name)?;
+        if !nums.contains(&num) {
+            nums.push(num);
+            nums.sort();
+            Self::write(&self.root.join(name), &serde_json::to_vec(&nums)?)?;
+        }
+        Ok(())
+    }
+}
+
+impl Backend for DirBackend {
+    fn get_stored_ids(&self) -> anyhow::Result<Vec<u32>> {
+        let mut ids = vec![];
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if let Some(num) = entry.file_name().to_str().and_then(|name| name.strip_prefix("xkcd_"))
+                && let Ok(num) = num.parse()
+                && entry.path().join(INFO).exists()
+            {
+                ids.push(num);
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn get_meta(&self, num: u32) -> anyhow::Result<Option<Xkcd>> {
+        Self::read(&self.comic_dir(num).join(INFO))?
+            .map(|json| Ok(serde_json::from_slice(&json)?))
+            .transpose()
+    }
+
+    fn insert_meta(&self, xkcd: &Xkcd) -> anyhow::Result<bool> {
+        let dir = self.comic_dir(xkcd.num);
+        let json = xkcd.to_json();
+        if Self::read(&dir.join(INFO))?.is_some_and(|stored| stored == json.as_bytes()) {
+            return Ok(false);
+        }
+        fs::create_dir_all(&dir)?;
+        Self::write(&dir.join(INFO), json.as_bytes())?;
+        Ok(true)
+    }
+
+    fn get_image(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.find_image(num, variant)? {
+            Some((path, _)) => Self::read(&path),
+            None => Ok(None),
+        }
+    }
+
+    fn read_image(&self, num: u32, variant: ImageVariant, offset: u64, len: usize) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some((path, _)) = self.find_image(num, variant)? else {
+            return Ok(None);
+        };
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![];
+        file.take(len as u64).read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+
+    fn get_image_size(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<u64>> {
+        match self.find_image(num, variant)? {
+            Some((path, _)) => Ok(Some(fs::metadata(path)?.len())),
+            None => Ok(None),
+        }
+    }
+
+    fn get_image_format(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<ImageFormat>> {
+        Ok(self.find_image(num, variant)?.map(|(_, format)| format))
+    }
+
+    fn insert_image(&self, num: u32, variant: ImageVariant, data: &[u8], format: ImageFormat) -> anyhow::Result<()> {
+        let old = self.find_image(num, variant)?;
+        let dir = self.comic_dir(num);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{}.{}", Self::image_stem(variant), format.extension()));
+        Self::write(&path, data)?;
+        // Only once the new image is in place, and only if it did not just replace the old one.
+        if let Some((old, _)) = old
+            && old != path
+        {
+            match fs::remove_file(old) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Going by the modification times, files are only rewritten when they change.
+    fn changed_since(&self, since: i64) -> anyhow::Result<Vec<u32>> {
+        let since = UNIX_EPOCH + Duration::from_secs(since.max(0) as u64);
+        let mut changed = vec![];
+        for num in self.get_stored_ids()? {
+            for entry in fs::read_dir(self.comic_dir(num))? {
+                if entry?.metadata()?.modified()? >= since {
+                    changed.push(num);
+                    break;
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    fn get_gaps(&self) -> anyhow::Result<Vec<u32>> { self.read_nums(GAPS) }
+
+    fn insert_gap(&self, num: u32) -> anyhow::Result<()> { self.insert_num(GAPS, num) }
+
+    fn get_missing_2x(&self) -> anyhow::Result<Vec<u32>> { self.read_nums(MISSING_2X) }
+
+    fn insert_missing_2x(&self, num: u32) -> anyhow::Result<()> { self.insert_num(MISSING_2X, num) }
+
+    fn search(&self, query: &str) -> anyhow::Result<Vec<u32>> {
+        let mut metas = vec![];
+        for num in self.get_stored_ids()? {
+            metas.extend(self.get_meta(num)?);
+        }
+        Ok(search_metas(metas, query))
+    }
+
+    fn get_stats(&self) -> anyhow::Result<Stats> { compute_stats(self) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempStore(DirBackend);
+
+    impl TempStore {
+        fn new() -> Self {
+            let root = std::env::temp_dir().join(format!("xkcd-dir-{}-{:08x}", process::id(), rand::random::<u32>()));
+            Self(DirBackend::open(&root).unwrap())
+        }
+
+        fn files(&self, num: u32) -> Vec<String> {
+            let mut files = fs::read_dir(self.0.comic_dir(num))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            files.sort();
+            files
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) { let _ = fs::remove_dir_all(&self.0.root); }
+    }
+
+    #[test]
+    fn insert_image_replaces_image_of_other_format() {
+        let store = TempStore::new();
+        store.0.insert_meta(&Xkcd::sample(1, "Barrel", "")).unwrap();
+        store
+            .0
+            .insert_image(1, ImageVariant::Standard, b"png", ImageFormat::Png)
+            .unwrap();
+        store
+            .0
+            .insert_image(1, ImageVariant::Double, b"png 2x", ImageFormat::Png)
+            .unwrap();
+        store
+            .0
+            .insert_image(1, ImageVariant::Standard, b"jpeg", ImageFormat::Jpeg)
+            .unwrap();
+
+        assert_eq!(store.files(1), ["image.jpg", "image_2x.png", "info.json"]);
+        assert_eq!(
+            store.0.get_image(1, ImageVariant::Standard).unwrap(),
+            Some(b"jpeg".to_vec())
+        );
+        assert_eq!(
+            store.0.get_image_format(1, ImageVariant::Standard).unwrap(),
+            Some(ImageFormat::Jpeg)
+        );
+        store
+            .0
+            .insert_image(1, ImageVariant::Standard, b"jpeg again", ImageFormat::Jpeg)
+            .unwrap();
+        assert_eq!(store.0.get_image_size(1, ImageVariant::Standard).unwrap(), Some(10));
+        assert_eq!(store.files(1), ["image.jpg", "image_2x.png", "info.json"]);
+    }
+}
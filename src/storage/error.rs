@@ -0,0 +1,54 @@
+use crate::api::ApiError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("not found")]
+    NotFound,
+    /// Exists or may exist, but can't be served right now: offline, network trouble or a locked database.
+    #[error("unavailable: {0}")]
+    Unavailable(String),
+    #[error("I/O error: {0}")]
+    Io(anyhow::Error),
+    #[error("corrupted: {0}")]
+    Corrupted(String),
+}
+
+impl From<anyhow::Error> for StorageError {
+    fn from(e: anyhow::Error) -> Self {
+        if e.is::<serde_json::Error>() {
+            return StorageError::Corrupted(e.to_string());
+        }
+        match e.downcast_ref::<rusqlite::Error>() {
+            Some(rusqlite::Error::SqliteFailure(err, _))
+                if matches!(
+                    err.code,
+                    rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked
+                ) =>
+            {
+                StorageError::Unavailable(e.to_string())
+            }
+            Some(
+                rusqlite::Error::FromSqlConversionFailure(..)
+                | rusqlite::Error::InvalidColumnType(..)
+                | rusqlite::Error::IntegralValueOutOfRange(..),
+            ) => StorageError::Corrupted(e.to_string()),
+            Some(rusqlite::Error::SqliteFailure(err, _)) if err.code == rusqlite::ErrorCode::DatabaseCorrupt => {
+                StorageError::Corrupted(e.to_string())
+            }
+            _ => StorageError::Io(e),
+        }
+    }
+}
+
+impl From<ApiError> for StorageError {
+    fn from(e: ApiError) -> Self {
+        match e {
+            ApiError::NotFound => StorageError::NotFound,
+            ApiError::RateLimited { .. } | ApiError::ServerError { .. } | ApiError::Network(_) => {
+                StorageError::Unavailable(e.to_string())
+            }
+            ApiError::Decode(_) => StorageError::Corrupted(e.to_string()),
+            ApiError::UnexpectedStatus(_) | ApiError::InvalidUrl(_) => StorageError::Io(e.into()),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Mutex,
+};
+
+use crate::{
+    image::{ImageFormat, ImageVariant},
+    storage::backend::{Backend, Stats, compute_stats, search_metas, slice},
+    xkcd::Xkcd,
+};
+
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    store: Mutex<MemoryStore>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryStore {
+    metas: BTreeMap<u32, Xkcd>,
+    images: HashMap<(u32, ImageVariant), (Vec<u8>, ImageFormat)>,
+    gaps: BTreeSet<u32>,
+    missing_2x: BTreeSet<u32>,
+}
+
+impl Backend for MemoryBackend {
+    fn get_stored_ids(&self) -> anyhow::Result<Vec<u32>> {
+        Ok(self.store.lock().unwrap().metas.keys().copied().collect())
+    }
+
+    fn get_meta(&self, num: u32) -> anyhow::Result<Option<Xkcd>> {
+        Ok(self.store.lock().unwrap().metas.get(&num).cloned())
+    }
+
+    fn insert_meta(&self, xkcd: &Xkcd) -> anyhow::Result<bool> {
+        let previous = self.store.lock().unwrap().metas.insert(xkcd.num, xkcd.clone());
+        Ok(previous.is_none_or(|previous| previous.to_json() != xkcd.to_json()))
+    }
+
+    fn get_image(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<Vec<u8>>> {
+        let store = self.store.lock().unwrap();
+        Ok(store.images.get(&(num, variant)).map(|(data, _)| data.clone()))
+    }
+
+    fn read_image(&self, num: u32, variant: ImageVariant, offset: u64, len: usize) -> anyhow::Result<Option<Vec<u8>>> {
+        let store = self.store.lock().unwrap();
+        Ok(store
+            .images
+            .get(&(num, variant))
+            .map(|(data, _)| slice(data, offset, len).to_vec()))
+    }
+
+    fn get_image_size(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<u64>> {
+        let store = self.store.lock().unwrap();
+        Ok(store.images.get(&(num, variant)).map(|(data, _)| data.len() as u64))
+    }
+
+    fn get_image_format(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<ImageFormat>> {
+        let store = self.store.lock().unwrap();
+        Ok(store.images.get(&(num, variant)).map(|(_, format)| *format))
+    }
+
+    fn insert_image(&self, num: u32, variant: ImageVariant, data: &[u8], format: ImageFormat) -> anyhow::Result<()> {
+        let mut store = self.store.lock().unwrap();
+        store.images.insert((num, variant), (data.to_vec(), format));
+        Ok(())
+    }
+
+    fn get_gaps(&self) -> anyhow::Result<Vec<u32>> { Ok(self.store.lock().unwrap().gaps.iter().copied().collect()) }
+
+    fn insert_gap(&self, num: u32) -> anyhow::Result<()> {
+        self.store.lock().unwrap().gaps.insert(num);
+        Ok(())
+    }
+
+    fn get_missing_2x(&self) -> anyhow::Result<Vec<u32>> {
+        Ok(self.store.lock().unwrap().missing_2x.iter().copied().collect())
+    }
+
+    fn insert_missing_2x(&self, num: u32) -> anyhow::Result<()> {
+        self.store.lock().unwrap().missing_2x.insert(num);
+        Ok(())
+    }
+
+    fn search(&self, query: &str) -> anyhow::Result<Vec<u32>> {
+        let metas = self.store.lock().unwrap().metas.values().cloned().collect::<Vec<_>>();
+        Ok(search_metas(metas, query))
+    }
+
+    fn get_stats(&self) -> anyhow::Result<Stats> { compute_stats(self) }
+}
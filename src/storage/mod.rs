@@ -0,0 +1,792 @@
+use std::{cmp::min, collections::HashSet, num::NonZeroU32, path::PathBuf};
+use std::sync::{Arc,Mutex};
+
+use indicatif::ProgressBar;
+use futures::{StreamExt, stream::FuturesUnordered};
+use governor::{Quota, RateLimiter};
+use log::{error, info, warn};
+use reqwest::Url;
+
+pub use crate::storage::{
+    backend::{BackendKind, ImageRecord, Resource, Stats, sha256_hex, slice},
+    cache::CachedStorage,
+    error::StorageError,
+};
+use crate::{
+    api::{self, ApiError, Fetched, RetryPolicy},
+    image::{ImageFormat, ImageVariant},
+    storage::{backend::Backend, dir::DirBackend, memory::MemoryBackend, sqlite::SqliteBackend},
+    xkcd::Xkcd,
+};
+
+mod backend;
+mod cache;
+mod dir;
+mod error;
+mod memory;
+mod sqlite;
+
+#[derive(Clone)]
+pub struct XkcdStorageConfig {
+    pub backend: BackendKind,
+    /// SQLite database file or store directory, depending on the backend.
+    pub db_path: PathBuf,
+    pub base_url: Url,
+    /// Replaces the host of image URLs returned by the API, e.g. to fetch images from a mirror.
+    pub image_base_url: Option<Url>,
+    pub retry_policy: RetryPolicy,
+    /// Only serve what is already in the database, never touch the network.
+    pub offline: bool,
+    /// Also fetch the high-resolution `_2x` images.
+    pub fetch_2x: bool,
+}
+
+pub trait Storage {
+    fn get_stored_ids(&self) -> Result<Vec<u32>, StorageError>;
+    // fn get_latest(&self) -> Option<Xkcd>;
+    fn get_meta(&self, num: u32) -> Result<Xkcd, StorageError>;
+    fn get_image(&self, num: u32, variant: ImageVariant) -> Result<Vec<u8>, StorageError>;
+    /// Up to `len` bytes of the image starting at `offset`.
+    fn read_image(&self, num: u32, variant: ImageVariant, offset: u64, len: usize) -> Result<Vec<u8>, StorageError>;
+    fn get_xkcd(&self, num: u32) -> Result<(Xkcd, Vec<u8>), StorageError>;
+    fn get_image_size(&self, num: u32, variant: ImageVariant) -> Result<u64, StorageError>;
+    fn get_image_format(&self, num: u32, variant: ImageVariant) -> Result<ImageFormat, StorageError>;
+    fn search(&self, query: &str) -> Result<Vec<u32>, StorageError>;
+    /// Forgets whatever is kept in memory about the comic, after it was changed behind this storage's back.
+    fn evict(&self, _num: u32) {}
+}
+
+/// What a refresh found out.
+#[derive(Debug, Default)]
+pub struct RefreshReport {
+    pub checked: usize,
+    /// Comics whose metadata changed upstream.
+    pub changed: Vec<u32>,
+    pub changed_images: Vec<(u32, ImageVariant)>,
+    pub failed: usize,
+}
+
+#[derive(Debug)]
+pub struct XkcdStorage {
+    backend: Box<dyn Backend>,
+    http_client: reqwest::Client,
+    base_url: Url,
+    image_base_url: Option<Url>,
+    retry_policy: RetryPolicy,
+    offline: bool,
+    fetch_2x: bool,
+}
+
+unsafe impl Send for XkcdStorage {}
+unsafe impl Sync for XkcdStorage {}
+
+impl XkcdStorage {
+    pub fn new(config: XkcdStorageConfig) -> Self {
+        let backend: Box<dyn Backend> = match config.backend {
+            BackendKind::Sqlite => Box::new(
+                SqliteBackend::open(&config.db_path)
+                    .unwrap_or_else(|e| panic!("Failed to open database at {}: {e}", config.db_path.display())),
+            ),
+            BackendKind::Dir => Box::new(
+                DirBackend::open(&config.db_path)
+                    .unwrap_or_else(|e| panic!("Failed to open store at {}: {e}", config.db_path.display())),
+            ),
+            BackendKind::Memory => Box::new(MemoryBackend::default()),
+        };
+
+        let http_client = reqwest::Client::new();
+
+        Self {
+            backend,
+            http_client,
+            base_url: config.base_url,
+            image_base_url: config.image_base_url,
+            retry_policy: config.retry_policy,
+            offline: config.offline,
+            fetch_2x: config.fetch_2x,
+        }
+    }
+
+    pub async fn ensure_range(&self, start: u32, end: u32) -> Result<(), ()> {
+        if self.offline {
+            info!("Offline, not fetching xkcd range");
+            return Ok(());
+        }
+
+        const RPS: u32 = 20;
+        let limiter = RateLimiter::direct(Quota::per_second(NonZeroU32::new(RPS).unwrap()));
+        let mut tasks = FuturesUnordered::new();
+
+        let latest = self
+            .get_latest()
+            .await
+            .map_err(|e| error!("Failed to get latest xkcd: {e}"))?;
+        let end = min(end, latest.num);
+        let start = min(start, end);
+        info!("Fetching xkcd range {}-{}", start, end);
+
+        let stored = self.get_stored_ids().map_err(|_| ())?;
+        // Stored comics are fetched again only for their missing 2x image, `get_xkcd` takes what is stored.
+        let without_2x = self.without_2x(&stored, start, end);
+        let ids: HashSet<_> = stored.into_iter().chain(self.get_gaps()).collect();
+        let mut missing = (start..=end).filter(|num| !ids.contains(num)).collect::<Vec<_>>();
+        missing.extend(without_2x);
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let missing_len = missing.len();
+        let progress_bar = Arc::new(Mutex::new(ProgressBar::new(missing_len as u64)));
+
+        for num in missing.into_iter() {
+            let permit = limiter.until_ready();
+            let bar = Arc::clone(&progress_bar);
+            let future = async move {
+                permit.await;
+                let _ = self.get_xkcd(num).await;
+                bar.lock().unwrap().inc(1);
+            };
+            tasks.push(future);
+        }
+
+        while tasks.next().await.is_some() {}
+        progress_bar.lock().unwrap().finish_with_message("Done!");
+        Ok(())
+    }
+
+    /// Stored comics in the range without a `_2x` image that may exist upstream, none unless 2x images are fetched.
+    fn without_2x(&self, stored: &[u32], start: u32, end: u32) -> Vec<u32> {
+        if !self.fetch_2x {
+            return vec![];
+        }
+        let missing_2x: HashSet<_> = self.get_missing_2x().into_iter().collect();
+        stored
+            .iter()
+            .copied()
+            .filter(|num| (start..=end).contains(num) && !missing_2x.contains(num))
+            .filter(|num| matches!(self.backend.get_image_size(*num, ImageVariant::Double), Ok(None)))
+            .collect()
+    }
+
+    /// Fetches comics newer than the newest stored one and returns their numbers.
+    pub async fn fetch_new(&self) -> Vec<u32> {
+        let Ok(stored) = self.get_stored_ids() else {
+            return vec![];
+        };
+        let stored: HashSet<_> = stored.into_iter().collect();
+        let last = stored.iter().max().copied().unwrap_or(0);
+        if self.ensure_range(last + 1, u32::MAX).await.is_err() {
+            return vec![];
+        }
+        self.get_stored_ids()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|num| !stored.contains(num))
+            .collect()
+    }
+
+    /// Fetches stored comics in the range again and stores what changed upstream. Comics and images that come with
+    /// validators from an earlier fetch are requested conditionally, so unchanged ones cost a `304 Not Modified`.
+    pub async fn refresh(&self, start: u32, end: u32, images: bool) -> Result<RefreshReport, StorageError> {
+        if self.offline {
+            return Err(StorageError::Unavailable("storage is offline".to_string()));
+        }
+
+        const RPS: u32 = 20;
+        let limiter = RateLimiter::direct(Quota::per_second(NonZeroU32::new(RPS).unwrap()));
+        let mut tasks = FuturesUnordered::new();
+
+        let mut ids = self.get_stored_ids()?;
+        ids.retain(|num| (start..=end).contains(num));
+        ids.sort_unstable();
+        info!("Refreshing {} stored xkcds", ids.len());
+        let progress_bar = Arc::new(Mutex::new(ProgressBar::new(ids.len() as u64)));
+
+        for num in ids {
+            let permit = limiter.until_ready();
+            let bar = Arc::clone(&progress_bar);
+            let future = async move {
+                permit.await;
+                let meta_changed = self.refresh_meta(num).await;
+                let mut images_changed = vec![];
+                if images && meta_changed.is_ok() {
+                    for variant in [ImageVariant::Standard, ImageVariant::Double] {
+                        images_changed.push((variant, self.refresh_image(num, variant).await));
+                    }
+                }
+                bar.lock().unwrap().inc(1);
+                (num, meta_changed, images_changed)
+            };
+            tasks.push(future);
+        }
+
+        let mut report = RefreshReport::default();
+        while let Some((num, meta_changed, images_changed)) = tasks.next().await {
+            report.checked += 1;
+            match meta_changed {
+                Ok(true) => report.changed.push(num),
+                Ok(false) => {}
+                Err(_) => report.failed += 1,
+            }
+            for (variant, changed) in images_changed {
+                match changed {
+                    Ok(true) => report.changed_images.push((num, variant)),
+                    Ok(false) | Err(StorageError::NotFound) => {}
+                    Err(_) => report.failed += 1,
+                }
+            }
+        }
+        progress_bar.lock().unwrap().finish_with_message("Done!");
+        report.changed.sort_unstable();
+        report
+            .changed_images
+            .sort_unstable_by_key(|(num, variant)| (*num, variant.scale()));
+        Ok(report)
+    }
+
+    /// Whether the comic changed upstream.
+    async fn refresh_meta(&self, num: u32) -> Result<bool, StorageError> {
+        let validators = self.backend.get_validators(num, Resource::Meta)?.unwrap_or_default();
+        let fetched =
+            api::fetch_xkcd_if_modified(&self.http_client, &self.base_url, &self.retry_policy, num, &validators)
+                .await
+                .map_err(|e| {
+                    error!("Failed to refresh xkcd {num}: {e}");
+                    e
+                })?;
+        let Some(fetched) = fetched else {
+            info!("Xkcd {num} not modified");
+            return Ok(false);
+        };
+        Ok(self.store_meta(Fetched {
+            value: fetched.value.into(),
+            validators: fetched.validators,
+        }))
+    }
+
+    /// Whether the stored image changed upstream, `NotFound` if it is not stored.
+    async fn refresh_image(&self, num: u32, variant: ImageVariant) -> Result<bool, StorageError> {
+        let Some(record) = self.backend.get_image_record(num, variant)? else {
+            return Err(StorageError::NotFound);
+        };
+        let meta = self.backend.get_meta(num)?.ok_or(StorageError::NotFound)?;
+        let validators = self
+            .backend
+            .get_validators(num, Resource::Image(variant))?
+            .unwrap_or_default();
+        let fetched = api::fetch_image_if_modified(
+            &self.http_client,
+            self.image_base_url.as_ref(),
+            &self.retry_policy,
+            &meta,
+            variant,
+            &validators,
+        )
+        .await
+        .map_err(|e| {
+            error!("Failed to refresh {variant:?} image for xkcd {num}: {e}");
+            e
+        })?;
+        let Some(fetched) = fetched else {
+            info!("{variant:?} image for xkcd {num} not modified");
+            return Ok(false);
+        };
+        let changed = match &record.sha256 {
+            Some(sha256) => *sha256 != sha256_hex(&fetched.value),
+            None => record.data != fetched.value,
+        };
+        let format = ImageFormat::detect(&fetched.value, &meta.image_url);
+        self.store_image(num, variant, &fetched, format);
+        Ok(changed)
+    }
+
+    /// Stores a fetched comic with its validators and returns whether it is new or changed.
+    fn store_meta(&self, fetched: Fetched<Xkcd>) -> bool {
+        let num = fetched.value.num;
+        let changed = self.backend.insert_meta(&fetched.value).unwrap_or_else(|e| {
+            error!("Failed to store xkcd {num}: {e}");
+            false
+        });
+        let _ = self
+            .backend
+            .set_validators(num, Resource::Meta, &fetched.validators)
+            .map_err(|e| error!("Failed to store validators of xkcd {num}: {e}"));
+        changed
+    }
+
+    fn store_image(&self, num: u32, variant: ImageVariant, fetched: &Fetched<Vec<u8>>, format: ImageFormat) {
+        let _ = self
+            .backend
+            .insert_image(num, variant, &fetched.value, format)
+            .and_then(|()| {
+                self.backend
+                    .set_validators(num, Resource::Image(variant), &fetched.validators)
+            })
+            .map_err(|e| {
+                error!("Failed to store {variant:?} image for xkcd {num}: {e:?}");
+            });
+    }
+
+    async fn get_latest(&self) -> Result<Xkcd, StorageError> {
+        let latest: Xkcd = api::fetch_latest(&self.http_client, &self.base_url, &self.retry_policy)
+            .await?
+            .into();
+        let _ = self.backend.insert_meta(&latest).map_err(|e| {
+            error!("Failed to store latest xkcd: {e}");
+        });
+        let _ = self.get_image(latest.num, ImageVariant::Standard).await;
+        self.ensure_2x(latest.num).await;
+        Ok(latest)
+    }
+
+    fn get_stored_ids(&self) -> Result<Vec<u32>, StorageError> {
+        self.backend.get_stored_ids().map_err(|e| {
+            error!("Failed to get stored IDs: {e}");
+            e.into()
+        })
+    }
+
+    fn changed_since(&self, since: i64) -> Result<Vec<u32>, StorageError> {
+        self.backend.changed_since(since).map_err(|e| {
+            error!("Failed to get changed xkcds: {e}");
+            e.into()
+        })
+    }
+
+    fn get_gaps(&self) -> Vec<u32> {
+        self.backend.get_gaps().unwrap_or_else(|e| {
+            error!("Failed to get gaps: {e}");
+            vec![]
+        })
+    }
+
+    fn get_missing_2x(&self) -> Vec<u32> {
+        self.backend.get_missing_2x().unwrap_or_else(|e| {
+            error!("Failed to get missing 2x images: {e}");
+            vec![]
+        })
+    }
+
+    async fn get_meta(&self, num: u32) -> Result<Xkcd, StorageError> {
+        match self.backend.get_meta(num) {
+            Ok(Some(xkcd)) => {
+                info!("Xkcd {num} already stored");
+                return Ok(xkcd);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to get xkcd {num}: {e}");
+                return Err(e.into());
+            }
+        }
+        if self.get_gaps().contains(&num) {
+            info!("Xkcd {num} is a known gap");
+            return Err(StorageError::NotFound);
+        }
+
+        if self.offline {
+            warn!("Xkcd {num} is not cached and storage is offline");
+            return Err(StorageError::Unavailable(format!("xkcd {num} is not cached")));
+        }
+        info!("Xkcd {num} not stored, fetching");
+        let fetched = match api::fetch_xkcd(&self.http_client, &self.base_url, &self.retry_policy, num).await {
+            Ok(fetched) => fetched,
+            Err(ApiError::NotFound) => {
+                self.record_gap(num);
+                return Err(StorageError::NotFound);
+            }
+            Err(e) => {
+                error!("Failed to fetch xkcd {num}: {e}");
+                return Err(e.into());
+            }
+        };
+        let xkcd: Xkcd = fetched.value.into();
+        self.store_meta(Fetched {
+            value: xkcd.clone(),
+            validators: fetched.validators,
+        });
+        Ok(xkcd)
+    }
+
+    /// Remembers that `num` does not exist upstream, unless it is past the newest stored comic and may simply not be
+    /// released yet.
+    fn record_gap(&self, num: u32) {
+        let last = self.get_stats().ok().and_then(|stats| stats.last);
+        if last.is_some_and(|last| num < last) {
+            warn!("Xkcd {num} does not exist, recording it as a gap");
+            let _ = self.backend.insert_gap(num).map_err(|e| {
+                error!("Failed to store gap {num}: {e}");
+            });
+        } else {
+            warn!("Xkcd {num} not found");
+        }
+    }
+
+    async fn get_image(&self, num: u32, variant: ImageVariant) -> Result<Vec<u8>, StorageError> {
+        match self.backend.get_image(num, variant) {
+            Ok(Some(img)) => {
+                info!("{variant:?} image for xkcd {num} already stored");
+                return Ok(img);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to get {variant:?} image for xkcd {num}: {e}");
+                return Err(e.into());
+            }
+        }
+
+        if variant == ImageVariant::Double && (!self.fetch_2x || self.get_missing_2x().contains(&num)) {
+            return Err(StorageError::NotFound);
+        }
+        info!("{variant:?} image for xkcd {num} not stored, fetching");
+        self.fetch_image(num, variant).await
+    }
+
+    /// Downloads the image and stores it, replacing whatever is stored.
+    async fn fetch_image(&self, num: u32, variant: ImageVariant) -> Result<Vec<u8>, StorageError> {
+        if self.offline {
+            warn!("{variant:?} image for xkcd {num} is not cached and storage is offline");
+            return Err(StorageError::Unavailable(format!(
+                "{variant:?} image for xkcd {num} is not cached"
+            )));
+        }
+        let meta = self.get_meta(num).await?;
+        let fetched = match api::fetch_image(
+            &self.http_client,
+            self.image_base_url.as_ref(),
+            &self.retry_policy,
+            &meta,
+            variant,
+        )
+        .await
+        {
+            Ok(fetched) => fetched,
+            Err(ApiError::NotFound) if variant == ImageVariant::Double => {
+                info!("No {variant:?} image for xkcd {num}, recording it");
+                let _ = self.backend.insert_missing_2x(num).map_err(|e| {
+                    error!("Failed to store missing {variant:?} image for xkcd {num}: {e}");
+                });
+                return Err(StorageError::NotFound);
+            }
+            Err(e) => {
+                error!("Failed to fetch {variant:?} image for xkcd {num}: {e:?}");
+                return Err(e.into());
+            }
+        };
+        let format = ImageFormat::detect(&fetched.value, &meta.image_url);
+        self.store_image(num, variant, &fetched, format);
+        Ok(fetched.value)
+    }
+
+    async fn read_image(
+        &self,
+        num: u32,
+        variant: ImageVariant,
+        offset: u64,
+        len: usize,
+    ) -> Result<Vec<u8>, StorageError> {
+        match self.backend.read_image(num, variant, offset, len) {
+            Ok(Some(data)) => return Ok(data),
+            Ok(None) => {}
+            Err(e) => {
+                error!("Failed to read {variant:?} image for xkcd {num}: {e}");
+                return Err(e.into());
+            }
+        }
+        let image = self.get_image(num, variant).await?;
+        Ok(slice(&image, offset, len).to_vec())
+    }
+
+    async fn ensure_2x(&self, num: u32) {
+        if self.fetch_2x {
+            let _ = self.get_image(num, ImageVariant::Double).await;
+        }
+    }
+
+    async fn get_xkcd(&self, num: u32) -> Result<(Xkcd, Vec<u8>), StorageError> {
+        let meta = self.get_meta(num).await?;
+        let image = self.get_image(num, ImageVariant::Standard).await?;
+        self.ensure_2x(num).await;
+        Ok((meta, image))
+    }
+
+    fn get_image_record(&self, num: u32, variant: ImageVariant) -> Result<ImageRecord, StorageError> {
+        match self.backend.get_image_record(num, variant) {
+            Ok(record) => record.ok_or(StorageError::NotFound),
+            Err(e) => {
+                error!("Failed to get {variant:?} image record for xkcd {num}: {e}");
+                Err(e.into())
+            }
+        }
+    }
+
+    fn get_image_size(&self, num: u32, variant: ImageVariant) -> Result<u64, StorageError> {
+        match self.backend.get_image_size(num, variant) {
+            Ok(size) => size.ok_or(StorageError::NotFound),
+            Err(e) => {
+                error!("Failed to get {variant:?} image size for xkcd {num}: {e}");
+                Err(e.into())
+            }
+        }
+    }
+
+    fn get_image_format(&self, num: u32, variant: ImageVariant) -> Result<ImageFormat, StorageError> {
+        match self.backend.get_image_format(num, variant) {
+            Ok(format) => format.ok_or(StorageError::NotFound),
+            Err(e) => {
+                error!("Failed to get {variant:?} image format for xkcd {num}: {e}");
+                Err(e.into())
+            }
+        }
+    }
+
+    fn insert_meta(&self, xkcd: &Xkcd) -> Result<bool, StorageError> { Ok(self.backend.insert_meta(xkcd)?) }
+
+    fn insert_image(
+        &self,
+        num: u32,
+        variant: ImageVariant,
+        data: &[u8],
+        format: ImageFormat,
+    ) -> Result<(), StorageError> {
+        Ok(self.backend.insert_image(num, variant, data, format)?)
+    }
+
+    fn get_stats(&self) -> Result<Stats, StorageError> {
+        self.backend.get_stats().map_err(|e| {
+            error!("Failed to get stats: {e}");
+            e.into()
+        })
+    }
+
+    fn search(&self, query: &str) -> Result<Vec<u32>, StorageError> {
+        self.backend.search(query).map_err(|e| {
+            error!("Failed to search for {query:?}: {e}");
+            e.into()
+        })
+    }
+}
+
+impl From<XkcdStorageConfig> for XkcdStorage {
+    fn from(config: XkcdStorageConfig) -> Self { Self::new(config) }
+}
+
+pub struct BlockingXkcdStorage {
+    storage: XkcdStorage,
+    rt: tokio::runtime::Runtime,
+}
+
+impl BlockingXkcdStorage {
+    pub fn ensure_range(&self, start: u32, end: u32) -> Result<(), ()> {
+        self.rt.block_on(self.storage.ensure_range(start, end))
+    }
+
+    pub fn fetch_new(&self) -> Vec<u32> { self.rt.block_on(self.storage.fetch_new()) }
+
+    pub fn refresh(&self, start: u32, end: u32, images: bool) -> Result<RefreshReport, StorageError> {
+        self.rt.block_on(self.storage.refresh(start, end, images))
+    }
+
+    pub fn get_stats(&self) -> Result<Stats, StorageError> { self.storage.get_stats() }
+
+    /// Comics stored or changed at or after the unix timestamp `since`, e.g. by a `refresh` in another process.
+    pub fn changed_since(&self, since: i64) -> Result<Vec<u32>, StorageError> { self.storage.changed_since(since) }
+
+    /// The stored image with what was recorded about it, without fetching anything.
+    pub fn get_image_record(&self, num: u32, variant: ImageVariant) -> Result<ImageRecord, StorageError> {
+        self.storage.get_image_record(num, variant)
+    }
+
+    /// Downloads the image again, replacing the stored one.
+    pub fn refetch_image(&self, num: u32, variant: ImageVariant) -> Result<Vec<u8>, StorageError> {
+        self.rt.block_on(self.storage.fetch_image(num, variant))
+    }
+
+    /// Stores `xkcd`, replacing what is stored for its number.
+    pub fn insert_meta(&self, xkcd: &Xkcd) -> Result<bool, StorageError> { self.storage.insert_meta(xkcd) }
+
+    /// Stores an image, replacing what is stored for the number and variant.
+    pub fn insert_image(
+        &self,
+        num: u32,
+        variant: ImageVariant,
+        data: &[u8],
+        format: ImageFormat,
+    ) -> Result<(), StorageError> {
+        self.storage.insert_image(num, variant, data, format)
+    }
+}
+
+#[cfg(test)]
+impl BlockingXkcdStorage {
+    /// Offline storage on the in-memory backend, filled through `insert_meta` and `insert_image`.
+    pub fn memory() -> Self {
+        XkcdStorage::new(XkcdStorageConfig {
+            backend: BackendKind::Memory,
+            db_path: PathBuf::new(),
+            base_url: Url::parse(api::XKCD_URL).unwrap(),
+            image_base_url: None,
+            retry_policy: RetryPolicy::default(),
+            offline: true,
+            fetch_2x: false,
+        })
+        .into()
+    }
+
+    /// Online storage on the in-memory backend that fetches 2x images and whose API and image host answer a request
+    /// for a path with the status and body `respond` returns, and the paths requested so far.
+    pub fn serving(respond: impl Fn(&str) -> (u16, Vec<u8>) + Send + 'static) -> (Self, Arc<Mutex<Vec<String>>>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let paths = requests.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0; 4096];
+                let len = stream.read(&mut request).unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..len]);
+                let path = request.split_whitespace().nth(1).unwrap_or_default().to_string();
+                let (status, body) = respond(&path);
+                paths.lock().unwrap().push(path);
+                let head = format!("HTTP/1.1 {status} Status\r\nContent-Length: {}\r\n\r\n", body.len());
+                let _ = stream.write_all(&[head.as_bytes(), &body].concat());
+            }
+        });
+        let storage = XkcdStorage::new(XkcdStorageConfig {
+            backend: BackendKind::Memory,
+            db_path: PathBuf::new(),
+            base_url: base_url.clone(),
+            image_base_url: Some(base_url),
+            retry_policy: RetryPolicy::default(),
+            offline: false,
+            fetch_2x: true,
+        });
+        (storage.into(), requests)
+    }
+
+    /// Records `num` as a comic that does not exist upstream.
+    pub fn insert_gap(&self, num: u32) { self.storage.backend.insert_gap(num).unwrap() }
+}
+
+impl From<XkcdStorage> for BlockingXkcdStorage {
+    fn from(storage: XkcdStorage) -> Self {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        Self { storage, rt }
+    }
+}
+impl Storage for BlockingXkcdStorage {
+    fn get_stored_ids(&self) -> Result<Vec<u32>, StorageError> { self.storage.get_stored_ids() }
+
+    fn get_meta(&self, num: u32) -> Result<Xkcd, StorageError> { self.rt.block_on(self.storage.get_meta(num)) }
+
+    fn get_image(&self, num: u32, variant: ImageVariant) -> Result<Vec<u8>, StorageError> {
+        self.rt.block_on(self.storage.get_image(num, variant))
+    }
+
+    fn read_image(&self, num: u32, variant: ImageVariant, offset: u64, len: usize) -> Result<Vec<u8>, StorageError> {
+        self.rt.block_on(self.storage.read_image(num, variant, offset, len))
+    }
+
+    fn get_xkcd(&self, num: u32) -> Result<(Xkcd, Vec<u8>), StorageError> {
+        self.rt.block_on(self.storage.get_xkcd(num))
+    }
+
+    fn get_image_size(&self, num: u32, variant: ImageVariant) -> Result<u64, StorageError> {
+        self.storage.get_image_size(num, variant)
+    }
+
+    fn get_image_format(&self, num: u32, variant: ImageVariant) -> Result<ImageFormat, StorageError> {
+        self.storage.get_image_format(num, variant)
+    }
+
+    fn search(&self, query: &str) -> Result<Vec<u32>, StorageError> { self.storage.search(query) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offline_serves_only_what_is_stored() {
+        let storage = BlockingXkcdStorage::memory();
+        assert!(storage.ensure_range(1, 10).is_ok());
+        assert!(matches!(storage.get_meta(1), Err(StorageError::Unavailable(_))));
+
+        storage
+            .insert_meta(&Xkcd::sample(1, "Barrel", "Don't we all."))
+            .unwrap();
+        assert_eq!(storage.get_meta(1).unwrap().title, "Barrel");
+        assert!(matches!(
+            storage.get_image(1, ImageVariant::Standard),
+            Err(StorageError::Unavailable(_))
+        ));
+        assert!(matches!(storage.get_xkcd(1), Err(StorageError::Unavailable(_))));
+
+        storage
+            .insert_image(1, ImageVariant::Standard, b"\x89PNG\r\n\x1a\n", ImageFormat::Png)
+            .unwrap();
+        assert_eq!(storage.get_xkcd(1).unwrap().1, b"\x89PNG\r\n\x1a\n");
+        assert!(matches!(
+            storage.get_image(1, ImageVariant::Double),
+            Err(StorageError::NotFound)
+        ));
+        assert!(matches!(
+            storage.refresh(1, 10, true),
+            Err(StorageError::Unavailable(_))
+        ));
+    }
+
+    #[test]
+    fn known_gaps_are_not_fetched_again() {
+        let (storage, requests) = BlockingXkcdStorage::serving(|_| (404, vec![]));
+        storage
+            .insert_meta(&Xkcd::sample(405, "Journal 4", "Three more journals."))
+            .unwrap();
+
+        assert!(matches!(storage.get_meta(404), Err(StorageError::NotFound)));
+        assert_eq!(*requests.lock().unwrap(), ["/404/info.0.json"]);
+        assert_eq!(storage.storage.get_gaps(), vec![404]);
+
+        assert!(matches!(storage.get_meta(404), Err(StorageError::NotFound)));
+        assert!(matches!(storage.get_xkcd(404), Err(StorageError::NotFound)));
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sync_backfills_2x_images_and_remembers_missing_ones() {
+        let latest = r#"{"month": "1", "num": 2, "link": "", "year": "2006", "news": "", "safe_title": "Petit Trees",
+            "transcript": "", "alt": "", "img": "https://imgs.xkcd.com/comics/tree.png", "title": "Petit Trees",
+            "day": "1"}"#;
+        let (storage, requests) = BlockingXkcdStorage::serving(move |path| match path {
+            "/info.0.json" => (200, latest.as_bytes().to_vec()),
+            "/comics/tree_2x.png" => (200, b"\x89PNG\r\n\x1a\n".to_vec()),
+            _ => (404, vec![]),
+        });
+        for num in [1, 2] {
+            storage.insert_meta(&Xkcd::sample(num, "Title", "Alt")).unwrap();
+            storage
+                .insert_image(num, ImageVariant::Standard, b"\x89PNG\r\n\x1a\n", ImageFormat::Png)
+                .unwrap();
+        }
+
+        storage.ensure_range(1, 2).unwrap();
+        let mut paths = requests.lock().unwrap().drain(..).collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(paths, ["/comics/tree_2x.png", "/comics/xkcd_1_2x.png", "/info.0.json"]);
+        assert!(storage.get_image(2, ImageVariant::Double).is_ok());
+        assert_eq!(storage.storage.get_missing_2x(), vec![1]);
+
+        storage.ensure_range(1, 2).unwrap();
+        assert!(matches!(
+            storage.get_image(1, ImageVariant::Double),
+            Err(StorageError::NotFound)
+        ));
+        assert_eq!(*requests.lock().unwrap(), ["/info.0.json"]);
+    }
+}
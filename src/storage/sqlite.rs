@@ -0,0 +1,80 @@
+use std::{path::Path, time::Duration};
+
+use rusqlite::Connection;
+
+use crate::{
+    api::Validators,
+    db,
+    image::{ImageFormat, ImageVariant},
+    storage::backend::{Backend, ImageRecord, Resource, Stats},
+    xkcd::Xkcd,
+};
+
+#[derive(Debug)]
+pub struct SqliteBackend {
+    conn: Connection,
+}
+
+impl SqliteBackend {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        // The mount and the live updater use separate connections to the same database.
+        conn.busy_timeout(Duration::from_secs(5))?;
+        db::db_init(&mut conn)?;
+        Ok(Self { conn })
+    }
+}
+
+impl Backend for SqliteBackend {
+    fn get_stored_ids(&self) -> anyhow::Result<Vec<u32>> { db::get_stored_ids(&self.conn) }
+
+    fn get_meta(&self, num: u32) -> anyhow::Result<Option<Xkcd>> { db::get_meta(&self.conn, num) }
+
+    fn insert_meta(&self, xkcd: &Xkcd) -> anyhow::Result<bool> { db::insert_meta(&self.conn, xkcd) }
+
+    fn get_image(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<Vec<u8>>> {
+        db::get_image(&self.conn, num, variant)
+    }
+
+    fn read_image(&self, num: u32, variant: ImageVariant, offset: u64, len: usize) -> anyhow::Result<Option<Vec<u8>>> {
+        db::read_image(&self.conn, num, variant, offset, len)
+    }
+
+    fn get_image_size(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<u64>> {
+        db::get_image_size(&self.conn, num, variant)
+    }
+
+    fn get_image_format(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<ImageFormat>> {
+        db::get_image_format(&self.conn, num, variant)
+    }
+
+    fn insert_image(&self, num: u32, variant: ImageVariant, data: &[u8], format: ImageFormat) -> anyhow::Result<()> {
+        Ok(db::insert_image(&self.conn, num, variant, data, format)?)
+    }
+
+    fn get_image_record(&self, num: u32, variant: ImageVariant) -> anyhow::Result<Option<ImageRecord>> {
+        db::get_image_record(&self.conn, num, variant)
+    }
+
+    fn get_validators(&self, num: u32, resource: Resource) -> anyhow::Result<Option<Validators>> {
+        db::get_validators(&self.conn, num, resource)
+    }
+
+    fn set_validators(&self, num: u32, resource: Resource, validators: &Validators) -> anyhow::Result<()> {
+        Ok(db::set_validators(&self.conn, num, resource, validators)?)
+    }
+
+    fn changed_since(&self, since: i64) -> anyhow::Result<Vec<u32>> { db::get_changed_since(&self.conn, since) }
+
+    fn get_gaps(&self) -> anyhow::Result<Vec<u32>> { db::get_gaps(&self.conn) }
+
+    fn insert_gap(&self, num: u32) -> anyhow::Result<()> { Ok(db::insert_gap(&self.conn, num)?) }
+
+    fn get_missing_2x(&self) -> anyhow::Result<Vec<u32>> { db::get_missing_2x(&self.conn) }
+
+    fn insert_missing_2x(&self, num: u32) -> anyhow::Result<()> { Ok(db::insert_missing_2x(&self.conn, num)?) }
+
+    fn search(&self, query: &str) -> anyhow::Result<Vec<u32>> { db::search(&self.conn, query) }
+
+    fn get_stats(&self) -> anyhow::Result<Stats> { db::get_stats(&self.conn) }
+}
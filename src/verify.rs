@@ -0,0 +1,184 @@
+use std::fmt::{self, Display};
+
+use anyhow::Context;
+
+use crate::{
+    image::{ImageFormat, ImageVariant},
+    storage::{BlockingXkcdStorage, ImageRecord, Storage, StorageError, sha256_hex},
+};
+
+#[derive(Debug)]
+pub enum Problem {
+    UnknownFormat,
+    Truncated(ImageFormat),
+    FormatMismatch { recorded: ImageFormat, actual: ImageFormat },
+    SizeMismatch { recorded: u64, actual: u64 },
+    HashMismatch,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::UnknownFormat => write!(f, "not a PNG, JPEG or GIF image"),
+            Problem::Truncated(format) => write!(f, "{} image is cut off", format.extension()),
+            Problem::FormatMismatch { recorded, actual } => {
+                write!(f, "recorded as {} but is {}", recorded.mime_type(), actual.mime_type())
+            }
+            Problem::SizeMismatch { recorded, actual } => {
+                write!(f, "recorded size is {recorded} bytes but {actual} bytes are stored")
+            }
+            Problem::HashMismatch => write!(f, "content does not match the recorded SHA-256"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub checked: usize,
+    pub corrupt: usize,
+    pub repaired: usize,
+}
+
+fn check(record: &ImageRecord) -> Vec<Problem> {
+    let mut problems = vec![];
+    match ImageFormat::sniff(&record.data) {
+        Some(format) if !format.is_complete(&record.data) => problems.push(Problem::Truncated(format)),
+        Some(_) => {}
+        None => problems.push(Problem::UnknownFormat),
+    }
+    if let (Some(recorded), Some(actual)) = (record.format, ImageFormat::sniff(&record.data))
+        && recorded != actual
+    {
+        problems.push(Problem::FormatMismatch { recorded, actual });
+    }
+    let actual = record.data.len() as u64;
+    if record.size != actual {
+        problems.push(Problem::SizeMismatch {
+            recorded: record.size,
+            actual,
+        });
+    }
+    if record
+        .sha256
+        .as_ref()
+        .is_some_and(|sha256| *sha256 != sha256_hex(&record.data))
+    {
+        problems.push(Problem::HashMismatch);
+    }
+    problems
+}
+
+/// Checks every stored image and prints the broken ones. With `repair`, broken images are downloaded again.
+pub fn verify(storage: &BlockingXkcdStorage, repair: bool) -> anyhow::Result<Report> {
+    let mut report = Report::default();
+    let mut ids = storage.get_stored_ids().context("Failed to list stored comics")?;
+    ids.sort_unstable();
+    for num in ids {
+        for variant in [ImageVariant::Standard, ImageVariant::Double] {
+            let record = match storage.get_image_record(num, variant) {
+                Ok(record) => record,
+                Err(StorageError::NotFound) => continue,
+                Err(e) => return Err(e).with_context(|| format!("Failed to read {variant:?} image of xkcd {num}")),
+            };
+            report.checked += 1;
+            let problems = check(&record);
+            if problems.is_empty() {
+                continue;
+            }
+            report.corrupt += 1;
+            for problem in &problems {
+                println!("xkcd {num} ({variant:?} image): {problem}");
+            }
+            if repair {
+                if let Err(e) = storage.refetch_image(num, variant) {
+                    println!("xkcd {num} ({variant:?} image): failed to fetch again: {e}");
+                    continue;
+                }
+                let problems = storage
+                    .get_image_record(num, variant)
+                    .map(|record| check(&record))
+                    .with_context(|| format!("Failed to read {variant:?} image of xkcd {num}"))?;
+                if problems.is_empty() {
+                    println!("xkcd {num} ({variant:?} image): fetched again");
+                    report.repaired += 1;
+                }
+                for problem in &problems {
+                    println!("xkcd {num} ({variant:?} image): still broken after fetching again: {problem}");
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xkcd::Xkcd;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\0IEND\xaeB`\x82";
+
+    fn record(data: &[u8], format: ImageFormat) -> ImageRecord {
+        ImageRecord {
+            data: data.to_vec(),
+            size: data.len() as u64,
+            format: Some(format),
+            sha256: Some(sha256_hex(data)),
+        }
+    }
+
+    /// Comic 1 with `data` stored as its image in `format`.
+    fn store(storage: &BlockingXkcdStorage, data: &[u8], format: ImageFormat) {
+        storage
+            .insert_meta(&Xkcd::sample(1, "Barrel", "Don't we all."))
+            .unwrap();
+        storage.insert_image(1, ImageVariant::Standard, data, format).unwrap();
+    }
+
+    #[test]
+    fn check_finds_each_problem() {
+        assert!(check(&record(PNG, ImageFormat::Png)).is_empty());
+
+        let truncated = record(&PNG[..PNG.len() - 1], ImageFormat::Png);
+        assert!(matches!(check(&truncated)[..], [Problem::Truncated(ImageFormat::Png)]));
+
+        let mismatch = record(PNG, ImageFormat::Jpeg);
+        assert!(matches!(check(&mismatch)[..], [Problem::FormatMismatch {
+            recorded: ImageFormat::Jpeg,
+            actual: ImageFormat::Png
+        }]));
+
+        let mut changed = record(PNG, ImageFormat::Png);
+        changed.sha256 = Some(sha256_hex(b"something else"));
+        assert!(matches!(check(&changed)[..], [Problem::HashMismatch]));
+    }
+
+    #[test]
+    fn verify_counts_broken_images() {
+        let storage = BlockingXkcdStorage::memory();
+        store(&storage, PNG, ImageFormat::Png);
+        let report = verify(&storage, false).unwrap();
+        assert_eq!((report.checked, report.corrupt), (1, 0));
+
+        for (data, format) in [(&PNG[..PNG.len() - 1], ImageFormat::Png), (PNG, ImageFormat::Jpeg)] {
+            let storage = BlockingXkcdStorage::memory();
+            store(&storage, data, format);
+            let report = verify(&storage, true).unwrap();
+            assert_eq!((report.checked, report.corrupt, report.repaired), (1, 1, 0));
+        }
+    }
+
+    #[test]
+    fn repair_counts_only_images_that_are_fine_afterwards() {
+        let (storage, _) = BlockingXkcdStorage::serving(|_| (200, PNG.to_vec()));
+        store(&storage, &PNG[..PNG.len() - 1], ImageFormat::Png);
+        let report = verify(&storage, true).unwrap();
+        assert_eq!((report.checked, report.corrupt, report.repaired), (1, 1, 1));
+        assert_eq!(verify(&storage, false).unwrap().corrupt, 0);
+
+        let (storage, _) = BlockingXkcdStorage::serving(|_| (200, PNG[..PNG.len() - 1].to_vec()));
+        store(&storage, &PNG[..PNG.len() - 1], ImageFormat::Png);
+        let report = verify(&storage, true).unwrap();
+        assert_eq!((report.checked, report.corrupt, report.repaired), (1, 1, 0));
+    }
+}
@@ -1,12 +1,12 @@
 use std::{fmt::Display, time::SystemTime};
 
 use chrono::{NaiveDate, TimeZone, Utc};
-use log::{error, info};
-use serde::Deserialize;
+use log::error;
+use serde::{Deserialize, Serialize};
 
 use crate::api::XkcdApiResponse;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Xkcd {
     pub num: u32,
     pub title: String,
@@ -16,12 +16,34 @@ pub struct Xkcd {
     pub transcript: String,
     pub link: String,
     pub release_date: NaiveDate,
+    pub news: String,
 }
 
 impl Xkcd {
     pub fn release_date_as_timestamp(&self) -> SystemTime {
         SystemTime::from(Utc.from_utc_datetime(&self.release_date.and_hms_opt(0, 0, 0).unwrap()))
     }
+
+    /// Contents of `info.json`.
+    pub fn to_json(&self) -> String { serde_json::to_string_pretty(self).expect("Failed to serialize xkcd") }
+}
+
+#[cfg(test)]
+impl Xkcd {
+    /// A comic with just enough filled in for tests.
+    pub fn sample(num: u32, title: &str, alt: &str) -> Self {
+        Self {
+            num,
+            title: title.to_string(),
+            safe_title: title.to_string(),
+            image_url: format!("https://imgs.xkcd.com/comics/xkcd_{num}.png"),
+            alt: alt.to_string(),
+            transcript: String::new(),
+            link: String::new(),
+            release_date: NaiveDate::from_ymd_opt(2008, 1, 1).unwrap() + chrono::Days::new(num.into()),
+            news: String::new(),
+        }
+    }
 }
 
 impl Display for Xkcd {
@@ -49,9 +71,6 @@ fn parse_date(year: &str, month: &str, day: &str) -> NaiveDate {
 impl From<XkcdApiResponse> for Xkcd {
     fn from(value: XkcdApiResponse) -> Self {
         let release_date = parse_date(&value.year, &value.month, &value.day);
-        if !value.news.is_empty() {
-            info!("Xkcd {} has news: {}", value.num, value.news);
-        }
         Self {
             num: value.num,
             title: value.title,
@@ -61,6 +80,7 @@ impl From<XkcdApiResponse> for Xkcd {
             transcript: value.transcript,
             link: value.link,
             release_date,
+            news: value.news,
         }
     }
 }